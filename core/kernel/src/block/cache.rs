@@ -0,0 +1,255 @@
+//! Block cache module
+//! Blocks of a device are kept in memory in LRU order. A modified block is marked dirty and written
+//! back when it is evicted or by `sync_all`, a block somebody still holds is never evicted.
+
+use alloc::{collections::VecDeque, sync::Arc, vec, vec::Vec};
+use core::{
+    mem::{align_of, size_of},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use log::{info, warn};
+
+use super::{BLOCK_SIZE, BlockDevice, BlockError};
+use crate::sync::{Mutex, SpinLock};
+
+/// Aligned so that small structures can be read in place
+#[repr(C, align(8))]
+struct BlockData([u8; BLOCK_SIZE]);
+
+/// Plain data, which can be read from and written to a block in place
+///
+/// # Safety
+/// Any bytes of its size must be a valid value, and it must have no padding
+pub unsafe trait Pod: Copy {}
+
+macro_rules! impl_pod {
+    ($($ty:ty),*) => {
+        $(unsafe impl Pod for $ty {})*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A block in the cache
+pub struct CachedBlock {
+    block_id: usize,
+    data: SpinLock<BlockData>,
+    /// Changed since it was read or written back, only changed with `data` locked
+    dirty: AtomicBool,
+}
+
+impl CachedBlock {
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Relaxed)
+    }
+
+    /// Check that a `T` fits in the block at `offset` and is aligned
+    fn check<T>(offset: usize) {
+        assert!(
+            offset + size_of::<T>() <= BLOCK_SIZE && offset.is_multiple_of(align_of::<T>()) && align_of::<T>() <= 8,
+            "Invalid access of {} bytes at offset {} of a block",
+            size_of::<T>(),
+            offset
+        );
+    }
+
+    /// Call `f` with the `T` at `offset` of the block
+    pub fn read<T: Pod, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> V {
+        Self::check::<T>(offset);
+        let data = self.data.lock();
+        f(unsafe { &*(data.0.as_ptr().add(offset) as *const T) })
+    }
+
+    /// Call `f` with the `T` at `offset` of the block to change it, the block becomes dirty
+    pub fn modify<T: Pod, V>(&self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
+        Self::check::<T>(offset);
+        let mut data = self.data.lock();
+        self.dirty.store(true, Ordering::Relaxed);
+        f(unsafe { &mut *(data.0.as_mut_ptr().add(offset) as *mut T) })
+    }
+
+    /// Write the block to `device` if it is dirty
+    fn write_back(&self, device: &dyn BlockDevice) -> Result<(), BlockError> {
+        let data = {
+            let data = self.data.lock();
+            if !self.dirty.swap(false, Ordering::Relaxed) {
+                return Ok(());
+            }
+            data.0
+        };
+        device
+            .write_block(self.block_id, &data)
+            .inspect_err(|_| self.dirty.store(true, Ordering::Relaxed))
+    }
+}
+
+/// An LRU cache of at most `capacity` blocks of a device, unless more blocks are held at the same time
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    capacity: usize,
+    /// From the least recently used to the most recently used
+    blocks: Mutex<VecDeque<Arc<CachedBlock>>>,
+}
+
+impl BlockCache {
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Self {
+        assert!(capacity > 0, "Block cache without capacity");
+        Self {
+            device,
+            capacity,
+            blocks: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Get the block `block_id`, it is read from the device if it isn't cached
+    pub fn get(&self, block_id: usize) -> Result<Arc<CachedBlock>, BlockError> {
+        let mut blocks = self.blocks.lock();
+        if let Some(index) = blocks.iter().position(|block| block.block_id == block_id) {
+            let block = blocks.remove(index).unwrap();
+            blocks.push_back(block.clone());
+            return Ok(block);
+        }
+        self.evict(&mut blocks)?;
+        let mut data = BlockData([0; BLOCK_SIZE]);
+        self.device.read_block(block_id, &mut data.0)?;
+        let block = Arc::new(CachedBlock {
+            block_id,
+            data: SpinLock::new(data),
+            dirty: AtomicBool::new(false),
+        });
+        blocks.push_back(block.clone());
+        Ok(block)
+    }
+
+    /// Make room for a block, evict the least recently used blocks which nobody holds
+    fn evict(&self, blocks: &mut VecDeque<Arc<CachedBlock>>) -> Result<(), BlockError> {
+        while blocks.len() >= self.capacity {
+            // the cache holds the only reference, and nobody can clone it while the cache is locked
+            let Some(index) = blocks.iter().position(|block| Arc::strong_count(block) == 1) else {
+                // all blocks are in use, grow beyond the capacity until some are released
+                break;
+            };
+            blocks[index].write_back(self.device.as_ref())?;
+            blocks.remove(index);
+        }
+        Ok(())
+    }
+
+    /// Write all dirty blocks back and flush the device
+    pub fn sync_all(&self) -> Result<(), BlockError> {
+        let blocks = self.blocks.lock();
+        for block in blocks.iter() {
+            block.write_back(self.device.as_ref())?;
+        }
+        self.device.flush()
+    }
+
+    /// Number of cached blocks and how many of them are dirty
+    pub fn stats(&self) -> (usize, usize) {
+        let blocks = self.blocks.lock();
+        (blocks.len(), blocks.iter().filter(|block| block.is_dirty()).count())
+    }
+}
+
+impl Drop for BlockCache {
+    fn drop(&mut self) {
+        if let Err(err) = self.sync_all() {
+            warn!("Failed to write back the block cache: {:?}", err);
+        }
+    }
+}
+
+/// Check LRU eviction, write-back and sync on a device in memory
+#[allow(unused)]
+pub fn block_cache_test() {
+    info!("Testing block cache...");
+
+    struct MemDisk {
+        data: SpinLock<Vec<u8>>,
+        reads: AtomicUsize,
+        writes: AtomicUsize,
+    }
+
+    impl BlockDevice for MemDisk {
+        fn num_blocks(&self) -> usize {
+            self.data.lock().len() / BLOCK_SIZE
+        }
+
+        fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+            let data = self.data.lock();
+            let block = data
+                .get(block_id * BLOCK_SIZE..(block_id + 1) * BLOCK_SIZE)
+                .ok_or(BlockError::OutOfRange)?;
+            buf.copy_from_slice(block);
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+            let mut data = self.data.lock();
+            let block = data
+                .get_mut(block_id * BLOCK_SIZE..(block_id + 1) * BLOCK_SIZE)
+                .ok_or(BlockError::OutOfRange)?;
+            block.copy_from_slice(buf);
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    let disk = Arc::new(MemDisk {
+        data: SpinLock::new(vec![0; 8 * BLOCK_SIZE]),
+        reads: AtomicUsize::new(0),
+        writes: AtomicUsize::new(0),
+    });
+    let counts = || (disk.reads.load(Ordering::Relaxed), disk.writes.load(Ordering::Relaxed));
+    let stored = |block_id: usize| {
+        let data = disk.data.lock();
+        u32::from_le_bytes(data[block_id * BLOCK_SIZE..][..4].try_into().unwrap())
+    };
+    let cache = BlockCache::new(disk.clone(), 2);
+
+    cache.get(0).unwrap().modify(0, |value: &mut u32| *value = 0xdead);
+    assert_eq!(cache.get(1).unwrap().read(0, |value: &u32| *value), 0);
+    assert_eq!(counts(), (2, 0));
+    // a hit, block 0 becomes the most recently used
+    assert_eq!(cache.get(0).unwrap().read(0, |value: &u32| *value), 0xdead);
+    assert_eq!(counts(), (2, 0));
+    // block 1 is evicted, it is clean
+    cache.get(2).unwrap();
+    assert_eq!(counts(), (3, 0));
+    // block 0 is evicted and written back
+    cache.get(3).unwrap();
+    assert_eq!(counts(), (4, 1));
+    assert_eq!(stored(0), 0xdead);
+    assert_eq!(cache.stats(), (2, 0));
+
+    // block 2 is held, so block 3 is evicted although block 2 is older
+    let held = cache.get(2).unwrap();
+    cache.get(3).unwrap();
+    cache.get(4).unwrap();
+    held.modify(4, |value: &mut u32| *value = 7);
+    cache.get(2).unwrap();
+    assert_eq!(counts(), (5, 1));
+    // all blocks are held, the cache grows
+    let also_held = cache.get(4).unwrap();
+    cache.get(5).unwrap();
+    assert_eq!(counts(), (6, 1));
+    assert_eq!(cache.stats(), (3, 1));
+    drop(also_held);
+
+    cache.sync_all().unwrap();
+    assert_eq!(counts().1, 2);
+    assert!(!held.is_dirty());
+    assert_eq!(cache.stats(), (3, 0));
+
+    held.modify(0, |value: &mut u32| *value = 42);
+    assert_eq!(cache.get(8).err(), Some(BlockError::OutOfRange));
+    drop(held);
+    // dropping the cache writes back the dirty block
+    drop(cache);
+    assert_eq!(stored(2), 42);
+    info!("Block cache test passed");
+}
@@ -0,0 +1,111 @@
+//! Block device module
+//! Drivers of block devices implement `BlockDevice` and register their devices here,
+//! the devices are numbered in the order they are found. Filesystems access them through a `BlockCache`.
+
+pub mod cache;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+pub use cache::block_cache_test;
+
+use crate::sync::SpinLock;
+
+/// Size of a block, the sector size of virtio-blk
+pub const BLOCK_SIZE: usize = 512;
+
+/// Error of a block device operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The block is beyond the end of the device
+    OutOfRange,
+    /// The buffer is not a whole number of blocks
+    InvalidBuffer,
+    /// Writing to a read-only device
+    ReadOnly,
+    /// The device reported an error
+    Io,
+    /// No memory for the request
+    NoMemory,
+}
+
+/// A device which reads and writes fixed size blocks
+pub trait BlockDevice: Send + Sync {
+    /// Number of blocks of the device
+    fn num_blocks(&self) -> usize;
+
+    /// Read the block `block_id` into `buf`, which is `BLOCK_SIZE` bytes
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf`, which is `BLOCK_SIZE` bytes, to the block `block_id`
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Read consecutive blocks from `start_block` into `buf`, a multiple of `BLOCK_SIZE` bytes
+    /// drivers which can transfer them in one request should override it
+    fn read_blocks(&self, start_block: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        if buf.is_empty() || !buf.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(BlockError::InvalidBuffer);
+        }
+        for (i, block) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            self.read_block(start_block + i, block)?;
+        }
+        Ok(())
+    }
+
+    /// Write `buf`, a multiple of `BLOCK_SIZE` bytes, to consecutive blocks from `start_block`
+    fn write_blocks(&self, start_block: usize, buf: &[u8]) -> Result<(), BlockError> {
+        if buf.is_empty() || !buf.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(BlockError::InvalidBuffer);
+        }
+        for (i, block) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+            self.write_block(start_block + i, block)?;
+        }
+        Ok(())
+    }
+
+    /// Wait until the written blocks are on the persistent storage
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+/// All block devices found at boot
+static BLOCK_DEVICES: SpinLock<Vec<Arc<dyn BlockDevice>>> = SpinLock::new(Vec::new());
+
+/// Add a block device, return its index
+pub fn register_block_device(device: Arc<dyn BlockDevice>) -> usize {
+    let mut devices = BLOCK_DEVICES.lock();
+    devices.push(device);
+    devices.len() - 1
+}
+
+/// Check that `device` reads its last blocks and rejects invalid requests, nothing is written
+/// device errors are returned, wrong results of the driver are bugs
+pub fn block_device_test(device: &dyn BlockDevice) -> Result<(), BlockError> {
+    let num_blocks = device.num_blocks();
+    if num_blocks == 0 {
+        return Ok(());
+    }
+    let last = num_blocks - 1;
+    let mut block = vec![0u8; BLOCK_SIZE];
+    device.read_block(last, &mut block)?;
+    assert_eq!(
+        device.read_block(num_blocks, &mut [0; BLOCK_SIZE]),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(device.read_block(last, &mut [0; 16]), Err(BlockError::InvalidBuffer));
+    if device.is_read_only() {
+        assert_eq!(device.write_block(last, &block), Err(BlockError::ReadOnly));
+    }
+    if last > 0 {
+        // a transfer of several blocks reads the same as single blocks
+        let mut blocks = vec![0u8; 2 * BLOCK_SIZE];
+        device.read_blocks(last - 1, &mut blocks)?;
+        assert_eq!(blocks[BLOCK_SIZE..], block);
+        assert_eq!(device.read_blocks(last, &mut blocks), Err(BlockError::OutOfRange));
+    }
+    Ok(())
+}
@@ -1,12 +1,44 @@
     .section .text.entry
     .globl _start
+# Entry of the boot hart, a0 = hartid, a1 = address of the device tree
 _start:
-    la sp, boot_stack_top
+    li t0, {MAX_HARTS}
+    bgeu a0, t0, boot_hart_unsupported
+    call boot_stack_setup
     call rust_main
 
+# The per-hart state is indexed by the hartid, a boot hart beyond MAX_HARTS can't run the kernel
+# report it on the last boot stack and shut down, no other hart has been started yet
+boot_hart_unsupported:
+    mv tp, a0
+    la sp, boot_stack_top
+    call rust_main_unsupported
+
+    .globl _start_secondary
+# Entry of the secondary harts started by SBI HSM, a0 = hartid, a1 = opaque
+_start_secondary:
+    call boot_stack_setup
+    call rust_main_secondary
+
+# Keep the hartid in tp and switch to the boot stack of the hart
+# secondary harts beyond the boot stacks(MAX_HARTS in config.rs) are never started, they are parked anyway
+boot_stack_setup:
+    mv tp, a0
+    li t0, {MAX_HARTS}
+    bgeu a0, t0, park
+    addi t0, a0, 1
+    slli t0, t0, {BOOT_STACK_SHIFT}
+    la sp, boot_stack
+    add sp, sp, t0
+    ret
+park:
+    wfi
+    j park
+
     .section .data.stack
     .globl boot_stack
+# A boot stack of BOOT_STACK_SIZE(config.rs) for each hart
 boot_stack:
-    .space 4096 * 16 
+    .space {BOOT_STACK_SIZE} * {MAX_HARTS}
     .globl boot_stack_top
 boot_stack_top:
@@ -1,5 +1,14 @@
 pub const PAGE_SIZE: usize = 0x1000; //Page size = 4KiB
 pub const PAGE_SIZE_BITS: usize = 0xc; //Page size = 12bits
 pub const MEMORY_END: usize = 0x80800000; //Available memory From 0x80000000 to 0x80800000 = 8MiB
-pub const KERNEL_HEAP_SIZE: usize = 0x200000; //Kernel heap size = 2MiB
+pub const KERNEL_HEAP_SIZE: usize = 0x200000; //Initial kernel heap size = 2MiB
+pub const KERNEL_HEAP_GROW_SIZE: usize = 0x10000; //Kernel heap grows by at least 64KiB
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1; //Trampoline page
+pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE; //Trap context page, just below the trampoline
+pub const USER_SPACE_END: usize = 1 << 38; //User space is the lower half of the SV39 address space
+pub const MMAP_BASE: usize = 0x10_0000_0000; //Anonymous mmap regions are placed from here upwards
+pub const USER_STACK_SIZE: usize = PAGE_SIZE * 2; //User stack size = 8KiB
+pub const KERNEL_STACK_SIZE: usize = PAGE_SIZE * 2; //Kernel stack size of each task = 8KiB
+pub const TICKS_PER_SEC: usize = 100; //Timer interrupts per second, a tick = 10ms
+pub const MAX_HARTS: usize = 8; //Harts with a boot stack, passed to boot/entry.asm
+pub const BOOT_STACK_SIZE: usize = PAGE_SIZE * 16; //Boot stack size of each hart = 64KiB, a power of two, passed to boot/entry.asm
@@ -1,18 +1,33 @@
 use core::fmt::{self, Write};
 
-use crate::sbi::console_write_char;
+use crate::{
+    drivers::uart,
+    sbi::{console_read, console_write},
+};
 
 struct StdOut;
 
 impl Write for StdOut {
+    /// Write to the UART, or through SBI if there is none
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.chars() {
-            console_write_char(c as usize);
+        if !uart::write_bytes(s.as_bytes()) {
+            console_write(s.as_bytes());
         }
         Ok(())
     }
 }
 
+/// Read the bytes received by the console into `buf` without blocking, return the number of bytes read
+pub fn read(buf: &mut [u8]) -> usize {
+    uart::read_bytes(buf).unwrap_or_else(|| console_read(buf))
+}
+
+/// Block the current task until the console receives something, return false without blocking
+/// if the console can only be polled
+pub fn wait_for_input() -> bool {
+    uart::wait_for_input()
+}
+
 pub fn print(args: fmt::Arguments) {
     StdOut.write_fmt(args).unwrap();
 }
@@ -0,0 +1,101 @@
+//! CPU module
+//! Identify the running hart, keep per-hart data and bring up the secondary harts through SBI HSM
+
+use core::{
+    array,
+    ops::Index,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use log::{info, warn};
+
+use crate::{config::MAX_HARTS, machine::machine_info, sbi::hart_start};
+
+/// Id of the hart which boots the kernel
+static BOOT_HART: AtomicUsize = AtomicUsize::new(0);
+
+/// Harts which have finished their initialization
+static ONLINE: [AtomicBool; MAX_HARTS] = [const { AtomicBool::new(false) }; MAX_HARTS];
+
+/// Get the id of the running hart, it is kept in tp while running in the kernel
+#[inline]
+pub fn hart_id() -> usize {
+    let id: usize;
+    unsafe {
+        core::arch::asm!("mv {}, tp", out(reg) id);
+    }
+    id
+}
+
+/// Record the running hart as the boot hart, called first thing in `rust_main`
+pub fn set_boot_hart() {
+    BOOT_HART.store(hart_id(), Ordering::Relaxed);
+}
+
+/// Check if the running hart is the boot hart, which drives the global clock
+pub fn is_boot_hart() -> bool {
+    hart_id() == BOOT_HART.load(Ordering::Relaxed)
+}
+
+/// Mark the running hart as online
+pub fn set_online() {
+    ONLINE[hart_id()].store(true, Ordering::Release);
+}
+
+/// Number of harts which are online
+pub fn online_harts() -> usize {
+    ONLINE.iter().filter(|online| online.load(Ordering::Acquire)).count()
+}
+
+/// Data with an instance for each hart, indexing it gives the instance of the running hart
+pub struct PerCpu<T> {
+    data: [T; MAX_HARTS],
+}
+
+impl<T> PerCpu<T> {
+    pub fn new(init: impl FnMut(usize) -> T) -> Self {
+        Self {
+            data: array::from_fn(init),
+        }
+    }
+
+    /// Get the instance of the running hart
+    pub fn get(&self) -> &T {
+        &self.data[hart_id()]
+    }
+}
+
+impl<T> Index<usize> for PerCpu<T> {
+    type Output = T;
+
+    fn index(&self, hart: usize) -> &T {
+        &self.data[hart]
+    }
+}
+
+/// Start all other harts found in the device tree, they enter `_start_secondary`
+/// return when all started harts are online
+pub fn start_secondary_harts() {
+    unsafe extern "C" {
+        fn _start_secondary();
+    }
+    let boot_hart = BOOT_HART.load(Ordering::Relaxed);
+    let mut started = 1;
+    for hart in machine_info().harts {
+        if hart == boot_hart {
+            continue;
+        }
+        if hart >= MAX_HARTS {
+            warn!("Hart {} is ignored, at most {} harts are supported", hart, MAX_HARTS);
+            continue;
+        }
+        match hart_start(hart, _start_secondary as usize, 0) {
+            Ok(()) => started += 1,
+            Err(err) => warn!("Failed to start hart {}: {:?}", hart, err),
+        }
+    }
+    while online_harts() < started {
+        core::hint::spin_loop();
+    }
+    info!("{} harts online", started);
+}
@@ -0,0 +1,67 @@
+//! DMA buffer module
+//! The kernel maps the physical memory identically, so a frame is addressed by the same number
+//! from the kernel and from devices
+
+use core::ptr;
+
+use crate::{
+    config::PAGE_SIZE,
+    memory::{AllocError, Frames, PhysAddr},
+};
+
+/// Physically contiguous zeroed pages shared with a device
+pub struct Dma {
+    frames: Frames,
+}
+
+impl Dma {
+    /// Allocate `pages` zeroed pages
+    pub fn new(pages: usize) -> Result<Self, AllocError> {
+        let dma = Self {
+            frames: Frames::alloc(pages)?,
+        };
+        unsafe { ptr::write_bytes(dma.ptr(0, dma.len(), 1), 0, dma.len()) };
+        Ok(dma)
+    }
+
+    /// Physical address of the first page, it is also the virtual address in the kernel space
+    pub fn paddr(&self) -> usize {
+        PhysAddr::from(self.frames.ppn).0
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.num * PAGE_SIZE
+    }
+
+    /// Pointer to `len` bytes at `offset` of the pages, aligned to `align`
+    /// no reference to the pages is ever made since the device may change them at any time
+    fn ptr(&self, offset: usize, len: usize, align: usize) -> *mut u8 {
+        assert!(
+            offset.checked_add(len).is_some_and(|end| end <= self.len()) && offset.is_multiple_of(align),
+            "Invalid DMA access of {} bytes at offset {}",
+            len,
+            offset
+        );
+        (self.paddr() + offset) as *mut u8
+    }
+
+    /// Read the `T` at `offset`, T must be valid for any bit pattern
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile(self.ptr(offset, size_of::<T>(), align_of::<T>()) as *const T) }
+    }
+
+    /// Write `value` at `offset`
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { ptr::write_volatile(self.ptr(offset, size_of::<T>(), align_of::<T>()) as *mut T, value) }
+    }
+
+    /// Copy the bytes at `offset` into `buf`, the device must not be writing them
+    pub fn copy_to_slice(&self, offset: usize, buf: &mut [u8]) {
+        unsafe { ptr::copy_nonoverlapping(self.ptr(offset, buf.len(), 1), buf.as_mut_ptr(), buf.len()) }
+    }
+
+    /// Copy `buf` to the bytes at `offset`, the device must not be reading them
+    pub fn copy_from_slice(&self, offset: usize, buf: &[u8]) {
+        unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), self.ptr(offset, buf.len(), 1), buf.len()) }
+    }
+}
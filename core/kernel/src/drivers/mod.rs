@@ -0,0 +1,6 @@
+//! Device drivers module
+
+mod dma;
+pub mod plic;
+pub mod uart;
+pub mod virtio;
@@ -0,0 +1,164 @@
+//! Platform-Level Interrupt Controller driver
+//! Every hart has an S-mode context at the PLIC. A registered interrupt source is enabled on the
+//! contexts of all harts with priority 1 and threshold 0, the hart which claims it first handles it.
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use core::ptr::{read_volatile, write_volatile};
+
+use log::{info, warn};
+use riscv::register::sie;
+
+use crate::{cpu::hart_id, machine::machine_info, sync::IrqSpinLock};
+
+/// Priority of source `irq` is at `base + PRIORITY_BASE + 4 * irq`
+const PRIORITY_BASE: usize = 0;
+/// Enable bits of context `ctx` are at `base + ENABLE_BASE + ENABLE_STRIDE * ctx`
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+/// Threshold of context `ctx` is at `base + CONTEXT_BASE + CONTEXT_STRIDE * ctx`, claim/complete follows it
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CLAIM_COMPLETE: usize = 4;
+/// Highest interrupt source, source 0 doesn't exist and claiming 0 means nothing is pending
+const MAX_IRQ: u32 = 1023;
+
+/// Handler of an interrupt source, called in interrupt context
+pub type IrqHandler = Arc<dyn Fn() + Send + Sync>;
+
+struct Plic {
+    base: usize,
+    /// S-mode context of each hart, as (hart, context)
+    contexts: Vec<(usize, usize)>,
+    handlers: BTreeMap<u32, IrqHandler>,
+}
+
+impl Plic {
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+
+    /// The S-mode context of the running hart
+    fn context(&self) -> Option<usize> {
+        let hart = hart_id();
+        self.contexts
+            .iter()
+            .find(|&&(context_hart, _)| context_hart == hart)
+            .map(|&(_, context)| context)
+    }
+
+    fn set_enabled(&self, context: usize, irq: u32, enabled: bool) {
+        let offset = ENABLE_BASE + ENABLE_STRIDE * context + 4 * (irq as usize / 32);
+        let bit = 1 << (irq % 32);
+        let bits = self.read(offset);
+        self.write(offset, if enabled { bits | bit } else { bits & !bit });
+    }
+
+    fn set_priority(&self, irq: u32, priority: u32) {
+        self.write(PRIORITY_BASE + 4 * irq as usize, priority);
+    }
+
+    fn set_threshold(&self, context: usize, threshold: u32) {
+        self.write(CONTEXT_BASE + CONTEXT_STRIDE * context, threshold);
+    }
+
+    fn claim(&self, context: usize) -> u32 {
+        self.read(CONTEXT_BASE + CONTEXT_STRIDE * context + CLAIM_COMPLETE)
+    }
+
+    fn complete(&self, context: usize, irq: u32) {
+        self.write(CONTEXT_BASE + CONTEXT_STRIDE * context + CLAIM_COMPLETE, irq);
+    }
+}
+
+/// The PLIC, None until it is found in the device tree
+static PLIC: IrqSpinLock<Option<Plic>> = IrqSpinLock::new(None);
+
+/// Initialize the PLIC found in the device tree, all sources start disabled
+pub fn init() {
+    let machine = machine_info();
+    let Some(device) = machine.plic else {
+        warn!("No PLIC, devices will be polled");
+        return;
+    };
+    info!(
+        "Initializing PLIC at {:#x}, contexts (hart, context): {:?}",
+        device.base, machine.plic_contexts
+    );
+    let plic = Plic {
+        base: device.base,
+        contexts: machine.plic_contexts,
+        handlers: BTreeMap::new(),
+    };
+    for &(_, context) in plic.contexts.iter() {
+        for word in 0..=MAX_IRQ as usize / 32 {
+            plic.write(ENABLE_BASE + ENABLE_STRIDE * context + 4 * word, 0);
+        }
+    }
+    *PLIC.lock() = Some(plic);
+}
+
+/// Let the running hart take external interrupts, called once on every hart
+pub fn init_hart() {
+    let plic = PLIC.lock();
+    let Some(plic) = plic.as_ref() else {
+        return;
+    };
+    let Some(context) = plic.context() else {
+        warn!("No PLIC context for hart {}", hart_id());
+        return;
+    };
+    plic.set_threshold(context, 0);
+    unsafe {
+        sie::set_sext();
+    }
+}
+
+/// Route interrupt source `irq` to `handler` on all harts, return false if there is no PLIC
+/// the handler runs with interrupts disabled and must not block
+pub fn register_irq(irq: u32, handler: impl Fn() + Send + Sync + 'static) -> bool {
+    assert!(irq > 0 && irq <= MAX_IRQ, "Invalid interrupt source {}", irq);
+    let mut plic = PLIC.lock();
+    let Some(plic) = plic.as_mut() else {
+        return false;
+    };
+    if plic.handlers.insert(irq, Arc::new(handler)).is_some() {
+        warn!("Handler of interrupt source {} is replaced", irq);
+    }
+    plic.set_priority(irq, 1);
+    for &(_, context) in plic.contexts.iter() {
+        plic.set_enabled(context, irq, true);
+    }
+    true
+}
+
+/// Handle the pending external interrupts of the running hart, called on the S-mode external interrupt
+pub fn handle_irq() {
+    let Some(context) = PLIC.lock().as_ref().and_then(Plic::context) else {
+        return;
+    };
+    loop {
+        let (irq, handler) = {
+            let plic = PLIC.lock();
+            let plic = plic.as_ref().unwrap();
+            let irq = plic.claim(context);
+            (irq, plic.handlers.get(&irq).cloned())
+        };
+        if irq == 0 {
+            break;
+        }
+        match handler {
+            Some(handler) => handler(),
+            None => {
+                // nobody handles it, disable it or it will fire forever
+                warn!("No handler for interrupt source {}, disabled", irq);
+                let plic = PLIC.lock();
+                plic.as_ref().unwrap().set_enabled(context, irq, false);
+            }
+        }
+        PLIC.lock().as_ref().unwrap().complete(context, irq);
+    }
+}
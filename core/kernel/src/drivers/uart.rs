@@ -0,0 +1,260 @@
+//! NS16550A UART driver
+//! Received bytes and bytes to send are buffered in ring buffers. Without a PLIC the buffers are
+//! drained by polling, otherwise the UART interrupt moves bytes in both directions and wakes up readers.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use log::info;
+
+use crate::{
+    drivers::plic::register_irq,
+    machine::machine_info,
+    sync::{IrqSpinLock, WaitQueue},
+};
+
+/// Receive buffer(read) / transmit holding register(write)
+const RBR_THR: usize = 0;
+/// Interrupt enable register
+const IER: usize = 1;
+/// FIFO control register(write)
+const FCR: usize = 2;
+/// Line control register
+const LCR: usize = 3;
+/// Modem control register
+const MCR: usize = 4;
+/// Line status register
+const LSR: usize = 5;
+
+const IER_RX_AVAILABLE: u8 = 0x01;
+const IER_THR_EMPTY: u8 = 0x02;
+/// Enable the FIFOs and clear both of them
+const FCR_ENABLE_CLEAR: u8 = 0x07;
+/// 8 data bits, no parity, 1 stop bit
+const LCR_8N1: u8 = 0x03;
+/// Divisor latch access bit
+const LCR_DLAB: u8 = 0x80;
+/// DTR, RTS and OUT2, OUT2 gates the interrupt line on many boards
+const MCR_DTR_RTS_OUT2: u8 = 0x0b;
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+/// Bytes the transmitter FIFO takes when THR is empty
+const TX_FIFO_SIZE: usize = 16;
+
+/// A fixed size FIFO of bytes
+struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// A NS16550A compatible UART and its buffers
+pub struct Uart {
+    base: usize,
+    rx: RingBuffer<256>,
+    tx: RingBuffer<1024>,
+    /// Set once the UART interrupt reaches the hart
+    irq_enabled: bool,
+}
+
+impl Uart {
+    /// Initialize the UART at `base`: 8N1, FIFOs enabled, interrupts off
+    fn new(base: usize) -> Self {
+        let mut uart = Self {
+            base,
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+            irq_enabled: false,
+        };
+        uart.write_reg(IER, 0);
+        // divisor 1 is the highest baud rate, qemu ignores it anyway
+        uart.write_reg(LCR, LCR_DLAB);
+        uart.write_reg(0, 1);
+        uart.write_reg(1, 0);
+        uart.write_reg(LCR, LCR_8N1);
+        uart.write_reg(FCR, FCR_ENABLE_CLEAR);
+        uart.write_reg(MCR, MCR_DTR_RTS_OUT2);
+        uart
+    }
+
+    fn read_reg(&self, reg: usize) -> u8 {
+        unsafe { read_volatile((self.base + reg) as *const u8) }
+    }
+
+    fn write_reg(&mut self, reg: usize, value: u8) {
+        unsafe { write_volatile((self.base + reg) as *mut u8, value) }
+    }
+
+    /// Move received bytes from the UART into the receive buffer, bytes are dropped if it is full
+    fn receive(&mut self) {
+        while self.read_reg(LSR) & LSR_DATA_READY != 0 {
+            let byte = self.read_reg(RBR_THR);
+            self.rx.push(byte);
+        }
+    }
+
+    /// Move bytes from the transmit buffer into the UART FIFO while it has room
+    fn transmit(&mut self) {
+        if self.read_reg(LSR) & LSR_THR_EMPTY == 0 {
+            return;
+        }
+        for _ in 0..TX_FIFO_SIZE {
+            match self.tx.pop() {
+                Some(byte) => self.write_reg(RBR_THR, byte),
+                None => break,
+            }
+        }
+    }
+
+    /// Enable the THR empty interrupt only while there is something to send
+    fn update_ier(&mut self) {
+        let tx_pending = if self.tx.is_empty() { 0 } else { IER_THR_EMPTY };
+        self.write_reg(IER, IER_RX_AVAILABLE | tx_pending);
+    }
+
+    /// Queue `bytes` for sending, wait for the UART whenever the buffer is full
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            while !self.tx.push(byte) {
+                self.transmit();
+            }
+        }
+        self.transmit();
+        if self.irq_enabled {
+            self.update_ier();
+        } else {
+            while !self.tx.is_empty() {
+                self.transmit();
+            }
+        }
+    }
+
+    /// Take a received byte
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if !self.irq_enabled {
+            self.receive();
+        }
+        self.rx.pop()
+    }
+
+    /// Let the UART raise interrupts, `handle_irq` must be called on them
+    pub fn enable_irq(&mut self) {
+        self.irq_enabled = true;
+        self.update_ier();
+    }
+
+    /// Handle the UART interrupt: receive, continue sending and rearm the interrupts
+    pub fn handle_irq(&mut self) {
+        self.receive();
+        self.transmit();
+        self.update_ier();
+    }
+}
+
+/// The console UART, None until it is found in the device tree
+static UART: IrqSpinLock<Option<Uart>> = IrqSpinLock::new(None);
+/// Tasks waiting for received bytes
+static RX_WAIT: WaitQueue = WaitQueue::new();
+
+/// Initialize the UART found in the device tree, make it interrupt-driven if its interrupt can be routed
+/// the PLIC must be initialized
+pub fn init() {
+    let Some(device) = machine_info().uart else {
+        return;
+    };
+    info!("Initializing UART at {:#x}", device.base);
+    *UART.lock() = Some(Uart::new(device.base));
+    if let Some(irq) = device.irq
+        && register_irq(irq, handle_irq)
+    {
+        UART.lock().as_mut().unwrap().enable_irq();
+    }
+}
+
+/// The UART interrupt handler
+fn handle_irq() {
+    let received = {
+        let mut uart = UART.lock();
+        let Some(uart) = uart.as_mut() else {
+            return;
+        };
+        uart.handle_irq();
+        !uart.rx.is_empty()
+    };
+    if received {
+        RX_WAIT.wake_all();
+    }
+}
+
+/// Write to the UART, return false if there is no UART or it can't be used now
+/// e.g. it is already locked on this hart by a print which panics
+pub fn write_bytes(bytes: &[u8]) -> bool {
+    if UART.is_held_by_current_hart() {
+        return false;
+    }
+    match UART.lock().as_mut() {
+        Some(uart) => {
+            uart.write_bytes(bytes);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Read the received bytes into `buf` without blocking, return the number of bytes read
+/// or None if there is no UART
+pub fn read_bytes(buf: &mut [u8]) -> Option<usize> {
+    let mut uart = UART.lock();
+    let uart = uart.as_mut()?;
+    let mut count = 0;
+    while count < buf.len() {
+        match uart.read_byte() {
+            Some(byte) => buf[count] = byte,
+            None => break,
+        }
+        count += 1;
+    }
+    Some(count)
+}
+
+/// Block the current task until there are received bytes, return false without blocking if
+/// the UART is polled, the caller has to poll then
+pub fn wait_for_input() -> bool {
+    let irq_enabled = UART.lock().as_ref().is_some_and(|uart| uart.irq_enabled);
+    if irq_enabled {
+        RX_WAIT.wait_if(|| UART.lock().as_ref().is_some_and(|uart| uart.rx.is_empty()));
+    }
+    irq_enabled
+}
@@ -0,0 +1,270 @@
+//! Virtio block device driver
+//! A request is a chain of three buffers in one DMA area: the header, the data and the status byte.
+//! Tasks sleep until the interrupt reports their request done, before the interrupt is routed
+//! or when there is no task yet the driver polls the used ring.
+
+use alloc::{vec, vec::Vec};
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::{Buffer, MmioTransport, VirtIOError, VirtQueue};
+use crate::{
+    block::{BLOCK_SIZE, BlockDevice, BlockError},
+    config::PAGE_SIZE,
+    drivers::dma::Dma,
+    sync::{IrqSpinLock, WaitQueue},
+    task::current_task,
+};
+
+/// The device is read-only
+const F_RO: u64 = 1 << 5;
+/// The device has a write cache which is flushed by a flush request
+const F_FLUSH: u64 = 1 << 9;
+
+/// Offset of the capacity in the configuration, in 512 byte sectors
+const CONFIG_CAPACITY: usize = 0;
+const SECTOR_SIZE: usize = 512;
+
+const REQ_IN: u32 = 0;
+const REQ_OUT: u32 = 1;
+const REQ_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+/// Written before the request is submitted, the device always overwrites it
+const STATUS_NONE: u8 = 0xff;
+
+/// Layout of the DMA area of a request
+const HEADER_LEN: usize = 16;
+const STATUS_OFFSET: usize = HEADER_LEN;
+const DATA_OFFSET: usize = 64;
+
+/// Descriptors of the request queue, 5 requests can be in flight
+const QUEUE_SIZE: u16 = 16;
+/// Descriptors of a request
+const REQUEST_DESCS: u16 = 3;
+/// Largest request, longer transfers are split so that their DMA areas stay small
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+struct BlkInner {
+    queue: VirtQueue,
+    /// Bytes written by the device of each finished request, indexed by its head descriptor
+    completed: Vec<Option<u32>>,
+}
+
+impl BlkInner {
+    /// Record the requests finished by the device
+    fn collect_used(&mut self) {
+        while let Some((head, len)) = self.queue.pop_used() {
+            self.completed[head as usize] = Some(len);
+        }
+    }
+
+    /// Take the request at `head` if it is finished, its descriptors are freed
+    fn take_completed(&mut self, head: u16) -> Option<u32> {
+        self.collect_used();
+        let len = self.completed[head as usize].take()?;
+        self.queue.free(head);
+        Some(len)
+    }
+}
+
+pub struct VirtIOBlk {
+    transport: MmioTransport,
+    /// In sectors
+    capacity: usize,
+    read_only: bool,
+    has_flush: bool,
+    inner: IrqSpinLock<BlkInner>,
+    /// Tasks waiting for their request or for free descriptors
+    wait: WaitQueue,
+    irq_enabled: AtomicBool,
+}
+
+impl VirtIOBlk {
+    /// Initialize the block device behind `transport`
+    pub fn new(transport: MmioTransport) -> Result<Self, VirtIOError> {
+        let features = transport.begin_init(F_RO | F_FLUSH)?;
+        let max = transport.queue_max_size(0).min(QUEUE_SIZE as u32);
+        if max < REQUEST_DESCS as u32 {
+            return Err(VirtIOError::QueueUnavailable);
+        }
+        let queue = VirtQueue::new(1 << max.ilog2()).map_err(|_| VirtIOError::NoMemory)?;
+        transport.setup_queue(0, &queue)?;
+        transport.finish_init();
+        let capacity = transport.config_read_u64(CONFIG_CAPACITY) as usize;
+        Ok(Self {
+            transport,
+            capacity,
+            read_only: features & F_RO != 0,
+            has_flush: features & F_FLUSH != 0,
+            inner: IrqSpinLock::new(BlkInner {
+                completed: vec![None; queue.size() as usize],
+                queue,
+            }),
+            wait: WaitQueue::new(),
+            irq_enabled: AtomicBool::new(false),
+        })
+    }
+
+    /// Number of 512 byte sectors
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The interrupt is routed to `handle_irq`, tasks may sleep on requests from now on
+    pub fn enable_irq(&self) {
+        self.irq_enabled.store(true, Ordering::Release);
+    }
+
+    /// Handle the interrupt of the device: collect the finished requests and wake up their tasks
+    pub fn handle_irq(&self) {
+        if self.transport.ack_interrupt() == 0 {
+            return;
+        }
+        self.inner.lock().collect_used();
+        self.wait.wake_all();
+    }
+
+    /// Sleep only if the interrupt will wake us up and there is a task to put to sleep
+    fn can_sleep(&self) -> bool {
+        self.irq_enabled.load(Ordering::Acquire) && current_task().is_some()
+    }
+
+    /// Run a request of `len` data bytes at `sector`, `dma` holds the data for writes
+    /// and receives it for reads, a request without data has no data buffer
+    fn request(&self, kind: u32, sector: usize, dma: &Dma, len: usize) -> Result<(), BlockError> {
+        // type, reserved and sector of the header
+        dma.write(0, kind.to_le());
+        dma.write(4, 0u32);
+        dma.write(8, (sector as u64).to_le());
+        dma.write(STATUS_OFFSET, STATUS_NONE);
+        let header = Buffer {
+            paddr: dma.paddr(),
+            len: HEADER_LEN,
+            device_writable: false,
+        };
+        let data = Buffer {
+            paddr: dma.paddr() + DATA_OFFSET,
+            len,
+            device_writable: kind == REQ_IN,
+        };
+        let status = Buffer {
+            paddr: dma.paddr() + STATUS_OFFSET,
+            len: 1,
+            device_writable: true,
+        };
+        let (with_data, without_data) = ([header, data, status], [header, status]);
+        let buffers: &[Buffer] = if len == 0 { &without_data } else { &with_data };
+        let head = loop {
+            let mut inner = self.inner.lock();
+            if let Some(head) = inner.queue.add(buffers) {
+                self.transport.notify(0);
+                break head;
+            }
+            drop(inner);
+            // all descriptors are in use by other requests
+            if self.can_sleep() {
+                self.wait.wait_if(|| self.inner.lock().queue.num_free() < REQUEST_DESCS);
+            } else {
+                spin_loop();
+            }
+        };
+        loop {
+            if self.inner.lock().take_completed(head).is_some() {
+                break;
+            }
+            if self.can_sleep() {
+                self.wait.wait_if(|| {
+                    let mut inner = self.inner.lock();
+                    inner.collect_used();
+                    inner.completed[head as usize].is_none()
+                });
+            } else {
+                spin_loop();
+            }
+        }
+        // our descriptors are free now, someone may be waiting for them
+        self.wait.wake_all();
+        match dma.read::<u8>(STATUS_OFFSET) {
+            STATUS_OK => Ok(()),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    /// Check the buffer and the range of a transfer of `buf_len` bytes at `block_id`
+    fn prepare(&self, block_id: usize, buf_len: usize) -> Result<(), BlockError> {
+        if buf_len == 0 || !buf_len.is_multiple_of(BLOCK_SIZE) {
+            return Err(BlockError::InvalidBuffer);
+        }
+        let sectors = buf_len / SECTOR_SIZE;
+        if block_id.checked_add(sectors).is_none_or(|end| end > self.capacity) {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Allocate the DMA area of a request of `len` data bytes
+    fn alloc_dma(&self, len: usize) -> Result<Dma, BlockError> {
+        Dma::new((DATA_OFFSET + len).div_ceil(PAGE_SIZE)).map_err(|_| BlockError::NoMemory)
+    }
+}
+
+impl BlockDevice for VirtIOBlk {
+    fn num_blocks(&self) -> usize {
+        self.capacity * SECTOR_SIZE / BLOCK_SIZE
+    }
+
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        if buf.len() != BLOCK_SIZE {
+            return Err(BlockError::InvalidBuffer);
+        }
+        self.read_blocks(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        if buf.len() != BLOCK_SIZE {
+            return Err(BlockError::InvalidBuffer);
+        }
+        self.write_blocks(block_id, buf)
+    }
+
+    /// Consecutive blocks are transferred in one request
+    fn read_blocks(&self, start_block: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.prepare(start_block, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(MAX_REQUEST_LEN).enumerate() {
+            let sector = start_block + i * MAX_REQUEST_LEN / SECTOR_SIZE;
+            let dma = self.alloc_dma(chunk.len())?;
+            self.request(REQ_IN, sector, &dma, chunk.len())?;
+            dma.copy_to_slice(DATA_OFFSET, chunk);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start_block: usize, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        self.prepare(start_block, buf.len())?;
+        for (i, chunk) in buf.chunks(MAX_REQUEST_LEN).enumerate() {
+            let sector = start_block + i * MAX_REQUEST_LEN / SECTOR_SIZE;
+            let dma = self.alloc_dma(chunk.len())?;
+            dma.copy_from_slice(DATA_OFFSET, chunk);
+            self.request(REQ_OUT, sector, &dma, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.has_flush {
+            return Ok(());
+        }
+        let dma = self.alloc_dma(0)?;
+        self.request(REQ_FLUSH, 0, &dma, 0)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
@@ -0,0 +1,258 @@
+//! Virtio over MMIO module
+//! The transport speaks both the legacy(version 1) and the modern(version 2) register layout,
+//! qemu uses the legacy one unless `virtio-mmio.force-legacy=false` is set
+
+pub mod blk;
+mod queue;
+
+use alloc::sync::Arc;
+use core::ptr::{read_volatile, write_volatile};
+
+use log::{info, warn};
+pub use queue::{Buffer, VirtQueue};
+
+use crate::{
+    block::{BlockDevice, block_device_test, register_block_device},
+    config::PAGE_SIZE,
+    drivers::plic::register_irq,
+    machine::{MmioDevice, machine_info},
+};
+
+/// "virt" in little endian
+const MAGIC: u32 = 0x7472_6976;
+
+const REG_MAGIC: usize = 0x000;
+const REG_VERSION: usize = 0x004;
+const REG_DEVICE_ID: usize = 0x008;
+const REG_DEVICE_FEATURES: usize = 0x010;
+const REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const REG_DRIVER_FEATURES: usize = 0x020;
+const REG_DRIVER_FEATURES_SEL: usize = 0x024;
+/// Legacy only
+const REG_GUEST_PAGE_SIZE: usize = 0x028;
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+/// Legacy only
+const REG_QUEUE_ALIGN: usize = 0x03c;
+/// Legacy only, the page number of the queue
+const REG_QUEUE_PFN: usize = 0x040;
+/// Modern only, and the address registers below
+const REG_QUEUE_READY: usize = 0x044;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_INTERRUPT_STATUS: usize = 0x060;
+const REG_INTERRUPT_ACK: usize = 0x064;
+const REG_STATUS: usize = 0x070;
+const REG_QUEUE_DESC: usize = 0x080;
+const REG_QUEUE_DRIVER: usize = 0x090;
+const REG_QUEUE_DEVICE: usize = 0x0a0;
+/// Start of the device specific configuration
+const REG_CONFIG: usize = 0x100;
+
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+/// The device complies with virtio 1.0, required by the modern transport
+const F_VERSION_1: u64 = 1 << 32;
+
+/// Device id of a block device
+pub const DEVICE_ID_BLOCK: u32 = 2;
+
+/// Error of setting up a virtio device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtIOError {
+    /// The device rejected the features
+    FeaturesRejected,
+    /// The queue doesn't exist or is already in use
+    QueueUnavailable,
+    NoMemory,
+}
+
+/// The registers of a virtio-mmio device
+pub struct MmioTransport {
+    base: usize,
+    version: u32,
+}
+
+impl MmioTransport {
+    /// Check the device at `base`, return None if it isn't a virtio device or the slot is empty
+    pub fn probe(base: usize) -> Option<Self> {
+        let transport = Self { base, version: 0 };
+        if transport.read(REG_MAGIC) != MAGIC {
+            return None;
+        }
+        let version = transport.read(REG_VERSION);
+        if !matches!(version, 1 | 2) {
+            warn!("Unsupported virtio-mmio version {} at {:#x}", version, base);
+            return None;
+        }
+        let transport = Self { base, version };
+        // device id 0 is an empty slot
+        (transport.device_id() != 0).then_some(transport)
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { write_volatile((self.base + reg) as *mut u32, value) }
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    pub fn device_id(&self) -> u32 {
+        self.read(REG_DEVICE_ID)
+    }
+
+    /// Reset the device and negotiate the features, return the accepted ones
+    /// the queues have to be set up and `finish_init` called afterwards
+    pub fn begin_init(&self, supported: u64) -> Result<u64, VirtIOError> {
+        self.write(REG_STATUS, 0);
+        self.write(REG_STATUS, STATUS_ACKNOWLEDGE);
+        self.write(REG_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let mut device_features = 0;
+        for sel in 0..2 {
+            self.write(REG_DEVICE_FEATURES_SEL, sel);
+            device_features |= (self.read(REG_DEVICE_FEATURES) as u64) << (32 * sel);
+        }
+        let supported = if self.is_legacy() {
+            supported
+        } else {
+            supported | F_VERSION_1
+        };
+        let features = device_features & supported;
+        for sel in 0..2 {
+            self.write(REG_DRIVER_FEATURES_SEL, sel);
+            self.write(REG_DRIVER_FEATURES, (features >> (32 * sel)) as u32);
+        }
+        if self.is_legacy() {
+            self.write(REG_GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        } else {
+            self.write(REG_STATUS, self.read(REG_STATUS) | STATUS_FEATURES_OK);
+            if self.read(REG_STATUS) & STATUS_FEATURES_OK == 0 {
+                self.write(REG_STATUS, self.read(REG_STATUS) | STATUS_FAILED);
+                return Err(VirtIOError::FeaturesRejected);
+            }
+        }
+        Ok(features)
+    }
+
+    /// Largest size of the queue `index`, 0 if it doesn't exist
+    pub fn queue_max_size(&self, index: u32) -> u32 {
+        self.write(REG_QUEUE_SEL, index);
+        self.read(REG_QUEUE_NUM_MAX)
+    }
+
+    /// Hand the queue `index` to the device
+    pub fn setup_queue(&self, index: u32, queue: &VirtQueue) -> Result<(), VirtIOError> {
+        self.write(REG_QUEUE_SEL, index);
+        let max = self.read(REG_QUEUE_NUM_MAX);
+        if max == 0 || max < queue.size() as u32 {
+            return Err(VirtIOError::QueueUnavailable);
+        }
+        self.write(REG_QUEUE_NUM, queue.size() as u32);
+        if self.is_legacy() {
+            if self.read(REG_QUEUE_PFN) != 0 {
+                return Err(VirtIOError::QueueUnavailable);
+            }
+            self.write(REG_QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(REG_QUEUE_PFN, (queue.desc_paddr() / PAGE_SIZE) as u32);
+        } else {
+            if self.read(REG_QUEUE_READY) != 0 {
+                return Err(VirtIOError::QueueUnavailable);
+            }
+            for (reg, paddr) in [
+                (REG_QUEUE_DESC, queue.desc_paddr()),
+                (REG_QUEUE_DRIVER, queue.avail_paddr()),
+                (REG_QUEUE_DEVICE, queue.used_paddr()),
+            ] {
+                self.write(reg, paddr as u32);
+                self.write(reg + 4, (paddr >> 32) as u32);
+            }
+            self.write(REG_QUEUE_READY, 1);
+        }
+        Ok(())
+    }
+
+    /// The driver is ready, the device may be used from now on
+    pub fn finish_init(&self) {
+        self.write(REG_STATUS, self.read(REG_STATUS) | STATUS_DRIVER_OK);
+    }
+
+    /// Tell the device there are new buffers in the queue `index`
+    pub fn notify(&self, index: u32) {
+        self.write(REG_QUEUE_NOTIFY, index);
+    }
+
+    /// Acknowledge the pending interrupts, return their bits
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(REG_INTERRUPT_STATUS);
+        if status != 0 {
+            self.write(REG_INTERRUPT_ACK, status);
+        }
+        status
+    }
+
+    /// Read a u32 at `offset` of the device configuration
+    pub fn config_read_u32(&self, offset: usize) -> u32 {
+        self.read(REG_CONFIG + offset)
+    }
+
+    /// Read a u64 at `offset` of the device configuration, as two u32 since the device may change it
+    pub fn config_read_u64(&self, offset: usize) -> u64 {
+        loop {
+            let high = self.config_read_u32(offset + 4);
+            let low = self.config_read_u32(offset);
+            if self.config_read_u32(offset + 4) == high {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+}
+
+/// Probe the virtio devices found in the device tree and register the supported ones
+/// the frame allocator and the PLIC must be initialized
+pub fn init() {
+    for device in machine_info().virtio {
+        let Some(transport) = MmioTransport::probe(device.base) else {
+            continue;
+        };
+        match transport.device_id() {
+            DEVICE_ID_BLOCK => init_blk(transport, device),
+            id => info!("Ignoring virtio device {} at {:#x}", id, device.base),
+        }
+    }
+}
+
+fn init_blk(transport: MmioTransport, device: MmioDevice) {
+    let blk = match blk::VirtIOBlk::new(transport) {
+        Ok(blk) => Arc::new(blk),
+        Err(err) => {
+            warn!("Failed to initialize virtio-blk at {:#x}: {:?}", device.base, err);
+            return;
+        }
+    };
+    if let Some(irq) = device.irq {
+        let handler = blk.clone();
+        if register_irq(irq, move || handler.handle_irq()) {
+            blk.enable_irq();
+        }
+    }
+    let index = register_block_device(blk.clone());
+    info!(
+        "virtio-blk {} at {:#x}: {} blocks{}",
+        index,
+        device.base,
+        blk.capacity(),
+        if blk.is_read_only() { ", read-only" } else { "" }
+    );
+    if let Err(err) = block_device_test(blk.as_ref()) {
+        warn!("virtio-blk {} failed the read check: {:?}", index, err);
+    }
+}
@@ -0,0 +1,229 @@
+//! Split virtqueue
+//! The descriptor table, the available ring and the used ring live in one DMA area in the legacy
+//! layout, the used ring starts on a new page. It works with both the legacy and the modern transport.
+
+use core::{
+    mem::size_of,
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{Ordering, fence},
+};
+
+use crate::{config::PAGE_SIZE, drivers::dma::Dma, memory::AllocError};
+
+/// The buffer continues in the descriptor `next`
+const DESC_F_NEXT: u16 = 1;
+/// The buffer is written by the device
+const DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// A buffer of a request: physical address, length and whether the device writes it
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub paddr: usize,
+    pub len: usize,
+    pub device_writable: bool,
+}
+
+pub struct VirtQueue {
+    dma: Dma,
+    size: u16,
+    /// Offsets of the rings in the DMA area
+    avail_offset: usize,
+    used_offset: usize,
+    /// Head of the list of free descriptors, linked by `next`
+    free_head: u16,
+    num_free: u16,
+    /// Next index of the available ring to fill
+    avail_idx: u16,
+    /// Next index of the used ring to consume
+    last_used_idx: u16,
+}
+
+impl VirtQueue {
+    /// Allocate a queue of `size` descriptors, `size` must be a power of 2
+    pub fn new(size: u16) -> Result<Self, AllocError> {
+        assert!(size.is_power_of_two(), "Queue size {} is not a power of 2", size);
+        let (avail_offset, used_offset, total) = Self::layout(size as usize);
+        let queue = Self {
+            dma: Dma::new(total / PAGE_SIZE)?,
+            size,
+            avail_offset,
+            used_offset,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+        };
+        for i in 0..size {
+            queue.write_desc(
+                i,
+                Descriptor {
+                    next: i + 1,
+                    ..Default::default()
+                },
+            );
+        }
+        Ok(queue)
+    }
+
+    /// Offsets of the available ring and the used ring and the total size of a queue of `size`
+    fn layout(size: usize) -> (usize, usize, usize) {
+        let avail_offset = size_of::<Descriptor>() * size;
+        // flags, idx, ring and used_event
+        let avail_size = 2 * (3 + size);
+        let used_offset = (avail_offset + avail_size).next_multiple_of(PAGE_SIZE);
+        // flags, idx, ring and avail_event
+        let used_size = 2 * 3 + size_of::<UsedElem>() * size;
+        (
+            avail_offset,
+            used_offset,
+            used_offset + used_size.next_multiple_of(PAGE_SIZE),
+        )
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    pub fn desc_paddr(&self) -> usize {
+        self.dma.paddr()
+    }
+
+    pub fn avail_paddr(&self) -> usize {
+        self.dma.paddr() + self.avail_offset
+    }
+
+    pub fn used_paddr(&self) -> usize {
+        self.dma.paddr() + self.used_offset
+    }
+
+    fn desc_ptr(&self, index: u16) -> *mut Descriptor {
+        (self.dma.paddr() as *mut Descriptor).wrapping_add(index as usize)
+    }
+
+    fn read_desc(&self, index: u16) -> Descriptor {
+        unsafe { read_volatile(self.desc_ptr(index)) }
+    }
+
+    fn write_desc(&self, index: u16, desc: Descriptor) {
+        unsafe { write_volatile(self.desc_ptr(index), desc) }
+    }
+
+    /// The `index`th u16 of the available ring, 0 is flags and 1 is idx
+    fn avail_ptr(&self, index: usize) -> *mut u16 {
+        ((self.dma.paddr() + self.avail_offset) as *mut u16).wrapping_add(index)
+    }
+
+    fn used_idx(&self) -> u16 {
+        let ptr = (self.dma.paddr() + self.used_offset + 2) as *const u16;
+        unsafe { read_volatile(ptr) }
+    }
+
+    fn used_elem(&self, index: u16) -> UsedElem {
+        let ptr = (self.dma.paddr() + self.used_offset + 4) as *const UsedElem;
+        unsafe { read_volatile(ptr.wrapping_add((index % self.size) as usize)) }
+    }
+
+    /// Chain `buffers` and make them available to the device, return the head descriptor
+    /// or None if there are not enough free descriptors. The device must be notified afterwards.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.num_free as usize {
+            return None;
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let desc = self.read_desc(index);
+            let last = i + 1 == buffers.len();
+            let mut flags = if last { 0 } else { DESC_F_NEXT };
+            if buffer.device_writable {
+                flags |= DESC_F_WRITE;
+            }
+            self.write_desc(
+                index,
+                Descriptor {
+                    addr: buffer.paddr as u64,
+                    len: buffer.len as u32,
+                    flags,
+                    next: desc.next,
+                },
+            );
+            if last {
+                self.free_head = desc.next;
+            } else {
+                index = desc.next;
+            }
+        }
+        self.num_free -= buffers.len() as u16;
+        unsafe {
+            write_volatile(self.avail_ptr(2 + (self.avail_idx % self.size) as usize), head);
+        }
+        // the descriptors must be visible before the index
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        unsafe {
+            write_volatile(self.avail_ptr(1), self.avail_idx);
+        }
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// Take a chain used by the device, return its head and the number of bytes written by the device
+    /// the chain stays allocated until `free` is called
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        fence(Ordering::SeqCst);
+        if self.last_used_idx == self.used_idx() {
+            return None;
+        }
+        let elem = self.used_elem(self.last_used_idx);
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        Some((elem.id as u16, elem.len))
+    }
+
+    /// Return the chain starting at `head` to the free list
+    pub fn free(&mut self, head: u16) {
+        let mut index = head;
+        loop {
+            let desc = self.read_desc(index);
+            self.num_free += 1;
+            if desc.flags & DESC_F_NEXT == 0 {
+                self.write_desc(
+                    index,
+                    Descriptor {
+                        next: self.free_head,
+                        ..Default::default()
+                    },
+                );
+                break;
+            }
+            self.write_desc(
+                index,
+                Descriptor {
+                    next: desc.next,
+                    ..Default::default()
+                },
+            );
+            index = desc.next;
+        }
+        self.free_head = head;
+    }
+}
@@ -0,0 +1,350 @@
+//! Flattened device tree module
+//! A minimal parser of the FDT(devicetree blob) passed in by the SBI firmware,
+//! it reads the header, the memory reservation block and the structure block into a flat node list
+
+use alloc::vec::Vec;
+use core::{ops::Range, slice, str};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// Size of the FDT header
+const HEADER_SIZE: usize = 40;
+
+/// Error of parsing a device tree blob
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdtError {
+    /// The magic number doesn't match
+    BadMagic,
+    /// An offset or length points outside the blob
+    Truncated,
+    /// An unknown token in the structure block
+    BadToken(u32),
+    /// A node or property name is not valid UTF-8
+    BadName,
+    /// A memory range overflows the address space
+    BadRange,
+}
+
+/// A property of a node
+#[derive(Debug, Clone, Copy)]
+pub struct FdtProp<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+impl<'a> FdtProp<'a> {
+    /// Read the value as a NUL-terminated string
+    pub fn as_str(&self) -> Option<&'a str> {
+        let bytes = self.value.split(|&b| b == 0).next()?;
+        str::from_utf8(bytes).ok()
+    }
+
+    /// Read the value as a list of NUL-terminated strings, e.g. `compatible`
+    pub fn as_str_list(&self) -> impl Iterator<Item = &'a str> {
+        self.value
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| str::from_utf8(s).ok())
+    }
+
+    /// Read the value as a big-endian u32 cell
+    pub fn as_u32(&self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.value.get(..4)?.try_into().ok()?))
+    }
+
+    /// Read the value as a list of cells
+    pub fn cells(&self) -> impl Iterator<Item = u32> + 'a {
+        self.value
+            .chunks_exact(4)
+            .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+    }
+}
+
+/// A node of the device tree
+#[derive(Debug)]
+pub struct FdtNode<'a> {
+    /// Name of the node, including the unit address, e.g. `uart@10000000`
+    pub name: &'a str,
+    /// Index of the parent node in `Fdt::nodes`, the root has no parent
+    pub parent: Option<usize>,
+    pub props: Vec<FdtProp<'a>>,
+}
+
+impl<'a> FdtNode<'a> {
+    pub fn prop(&self, name: &str) -> Option<&FdtProp<'a>> {
+        self.props.iter().find(|prop| prop.name == name)
+    }
+
+    /// Name of the node without the unit address
+    pub fn base_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+
+    /// Check if the node is compatible with `compatible`
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.prop("compatible")
+            .is_some_and(|prop| prop.as_str_list().any(|c| c == compatible))
+    }
+
+    /// A node is enabled unless its status says otherwise
+    pub fn is_enabled(&self) -> bool {
+        self.prop("status")
+            .and_then(|prop| prop.as_str())
+            .is_none_or(|status| status == "okay" || status == "ok")
+    }
+
+    pub fn device_type(&self) -> Option<&'a str> {
+        self.prop("device_type").and_then(|prop| prop.as_str())
+    }
+}
+
+/// A parsed device tree, it borrows the blob
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    pub nodes: Vec<FdtNode<'a>>,
+    /// Regions listed in the memory reservation block
+    pub mem_reserved: Vec<Range<usize>>,
+}
+
+/// Read a big-endian u32 at `offset`
+fn be32(data: &[u8], offset: usize) -> Result<u32, FdtError> {
+    let bytes = data.get(offset..offset + 4).ok_or(FdtError::Truncated)?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Read a big-endian u64 at `offset`
+fn be64(data: &[u8], offset: usize) -> Result<u64, FdtError> {
+    Ok(((be32(data, offset)? as u64) << 32) | be32(data, offset + 4)? as u64)
+}
+
+/// Read a NUL-terminated string at `offset`
+fn cstr(data: &[u8], offset: usize) -> Result<&str, FdtError> {
+    let bytes = data.get(offset..).ok_or(FdtError::Truncated)?;
+    let len = bytes.iter().position(|&b| b == 0).ok_or(FdtError::Truncated)?;
+    str::from_utf8(&bytes[..len]).map_err(|_| FdtError::BadName)
+}
+
+impl<'a> Fdt<'a> {
+    /// Parse the device tree blob at physical address `addr`
+    ///
+    /// # Safety
+    /// `addr` must point to a readable device tree blob which lives as long as 'a
+    pub unsafe fn from_ptr(addr: usize) -> Result<Self, FdtError> {
+        let header = unsafe { slice::from_raw_parts(addr as *const u8, HEADER_SIZE) };
+        if be32(header, 0)? != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let total_size = be32(header, 4)? as usize;
+        Self::parse(unsafe { slice::from_raw_parts(addr as *const u8, total_size) })
+    }
+
+    /// Parse a device tree blob
+    pub fn parse(data: &'a [u8]) -> Result<Self, FdtError> {
+        if be32(data, 0)? != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let mut fdt = Self {
+            data,
+            nodes: Vec::new(),
+            mem_reserved: Vec::new(),
+        };
+        fdt.parse_mem_reserved(be32(data, 16)? as usize)?;
+        fdt.parse_struct(be32(data, 8)? as usize, be32(data, 12)? as usize)?;
+        Ok(fdt)
+    }
+
+    /// Size of the blob in bytes
+    pub fn total_size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn parse_mem_reserved(&mut self, mut offset: usize) -> Result<(), FdtError> {
+        loop {
+            let address = be64(self.data, offset)? as usize;
+            let size = be64(self.data, offset + 8)? as usize;
+            if address == 0 && size == 0 {
+                return Ok(());
+            }
+            let end = address.checked_add(size).ok_or(FdtError::BadRange)?;
+            self.mem_reserved.push(address..end);
+            offset += 16;
+        }
+    }
+
+    fn parse_struct(&mut self, mut offset: usize, strings: usize) -> Result<(), FdtError> {
+        let data = self.data;
+        // the node being parsed at each depth
+        let mut stack: Vec<usize> = Vec::new();
+        loop {
+            let token = be32(data, offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(data, offset)?;
+                    offset = (offset + name.len() + 1).next_multiple_of(4);
+                    self.nodes.push(FdtNode {
+                        name,
+                        parent: stack.last().copied(),
+                        props: Vec::new(),
+                    });
+                    stack.push(self.nodes.len() - 1);
+                }
+                FDT_END_NODE => {
+                    stack.pop();
+                }
+                FDT_PROP => {
+                    let len = be32(data, offset)? as usize;
+                    let name = cstr(data, strings + be32(data, offset + 4)? as usize)?;
+                    let value = data.get(offset + 8..offset + 8 + len).ok_or(FdtError::Truncated)?;
+                    offset = (offset + 8 + len).next_multiple_of(4);
+                    let node = *stack.last().ok_or(FdtError::BadToken(token))?;
+                    self.nodes[node].props.push(FdtProp { name, value });
+                }
+                FDT_NOP => {}
+                FDT_END => return Ok(()),
+                _ => return Err(FdtError::BadToken(token)),
+            }
+        }
+    }
+
+    /// Find a node by its full path, e.g. `/cpus`, unit addresses may be omitted
+    pub fn find_node(&self, path: &str) -> Option<&FdtNode<'a>> {
+        let mut current = self.nodes.iter().position(|node| node.parent.is_none())?;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            current = self.children(current).find(|&child| {
+                let node = &self.nodes[child];
+                node.name == name || node.base_name() == name
+            })?;
+        }
+        Some(&self.nodes[current])
+    }
+
+    /// Find the index of the node with `phandle`
+    pub fn find_phandle(&self, phandle: u32) -> Option<usize> {
+        self.nodes
+            .iter()
+            .position(|node| node.prop("phandle").and_then(|prop| prop.as_u32()) == Some(phandle))
+    }
+
+    /// Indexes of the children of the node at `index`
+    pub fn children(&self, index: usize) -> impl Iterator<Item = usize> + '_ {
+        (0..self.nodes.len()).filter(move |&child| self.nodes[child].parent == Some(index))
+    }
+
+    /// Read `#address-cells` and `#size-cells` of a node, which apply to its children
+    /// the defaults are 2 and 1
+    fn cells_of(&self, index: Option<usize>) -> (usize, usize) {
+        let Some(node) = index.map(|index| &self.nodes[index]) else {
+            return (2, 1);
+        };
+        let address_cells = node.prop("#address-cells").and_then(|prop| prop.as_u32());
+        let size_cells = node.prop("#size-cells").and_then(|prop| prop.as_u32());
+        (address_cells.unwrap_or(2) as usize, size_cells.unwrap_or(1) as usize)
+    }
+
+    /// Decode the `reg` property of the node at `index` into address ranges
+    /// entries which overflow the address space are skipped
+    pub fn reg(&self, index: usize) -> Vec<Range<usize>> {
+        let node = &self.nodes[index];
+        let (address_cells, size_cells) = self.cells_of(node.parent);
+        let Some(reg) = node.prop("reg") else {
+            return Vec::new();
+        };
+        if address_cells + size_cells == 0 {
+            return Vec::new();
+        }
+        let cells: Vec<u32> = reg.cells().collect();
+        let read = |cells: &[u32]| cells.iter().fold(0usize, |value, &cell| (value << 32) | cell as usize);
+        cells
+            .chunks_exact(address_cells + size_cells)
+            .filter_map(|entry| {
+                let address = read(&entry[..address_cells]);
+                let end = address.checked_add(read(&entry[address_cells..]))?;
+                Some(address..end)
+            })
+            .collect()
+    }
+}
+
+/// Parse a small hand-built blob
+#[allow(unused)]
+pub fn fdt_test() {
+    log::info!("Testing device tree parser...");
+    let strings = b"#address-cells\0#size-cells\0reg\0device_type\0bootargs\0";
+    let mut structure: Vec<u8> = Vec::new();
+    let node = |structure: &mut Vec<u8>, name: &[u8]| {
+        structure.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+        structure.extend_from_slice(name);
+        structure.push(0);
+        structure.resize(structure.len().next_multiple_of(4), 0);
+    };
+    let prop = |structure: &mut Vec<u8>, name_offset: u32, value: &[u8]| {
+        structure.extend_from_slice(&FDT_PROP.to_be_bytes());
+        structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        structure.extend_from_slice(&name_offset.to_be_bytes());
+        structure.extend_from_slice(value);
+        structure.resize(structure.len().next_multiple_of(4), 0);
+    };
+    node(&mut structure, b"");
+    prop(&mut structure, 0, &2u32.to_be_bytes());
+    prop(&mut structure, 15, &1u32.to_be_bytes());
+    node(&mut structure, b"memory@80000000");
+    prop(&mut structure, 31, b"memory\0");
+    prop(&mut structure, 27, &[0, 0, 0, 0, 0x80, 0, 0, 0, 0x08, 0, 0, 0]);
+    structure.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+    node(&mut structure, b"chosen");
+    prop(&mut structure, 43, b"console=ttyS0\0");
+    structure.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+    structure.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+    structure.extend_from_slice(&FDT_END.to_be_bytes());
+
+    // header, then one reservation and the terminator, the structure block and the strings block
+    let rsvmap = HEADER_SIZE;
+    let struct_offset = rsvmap + 32;
+    let strings_offset = struct_offset + structure.len();
+    let total = strings_offset + strings.len();
+    let mut blob: Vec<u8> = Vec::new();
+    for word in [
+        FDT_MAGIC,
+        total as u32,
+        struct_offset as u32,
+        strings_offset as u32,
+        rsvmap as u32,
+        17,
+        16,
+        0,
+        strings.len() as u32,
+        structure.len() as u32,
+    ] {
+        blob.extend_from_slice(&word.to_be_bytes());
+    }
+    blob.extend_from_slice(&0x8700_0000u64.to_be_bytes());
+    blob.extend_from_slice(&0x1000u64.to_be_bytes());
+    blob.extend_from_slice(&[0; 16]);
+    blob.extend_from_slice(&structure);
+    blob.extend_from_slice(strings);
+
+    let fdt = Fdt::parse(&blob).expect("Failed to parse the test device tree");
+    assert_eq!(fdt.total_size(), total);
+    assert_eq!(fdt.mem_reserved.len(), 1);
+    assert_eq!(fdt.mem_reserved[0], 0x8700_0000..0x8700_1000);
+    assert_eq!(fdt.nodes.len(), 3);
+    let memory = fdt.find_node("/memory").unwrap();
+    assert_eq!(memory.device_type(), Some("memory"));
+    assert_eq!(fdt.reg(1).len(), 1);
+    assert_eq!(fdt.reg(1)[0], 0x8000_0000..0x8800_0000);
+    let chosen = fdt.find_node("/chosen").unwrap();
+    assert_eq!(
+        chosen.prop("bootargs").and_then(|prop| prop.as_str()),
+        Some("console=ttyS0")
+    );
+    assert!(fdt.find_node("/cpus").is_none());
+    blob[0] = 0;
+    assert_eq!(Fdt::parse(&blob).err(), Some(FdtError::BadMagic));
+    log::info!("Device tree parser test passed!");
+}
@@ -0,0 +1,39 @@
+//! Loader module
+//! The user programs are linked into the kernel image by `apps.S`, they are found by name
+
+use alloc::vec::Vec;
+use core::{arch::global_asm, ffi::CStr, slice};
+
+global_asm!(include_str!("apps.S"));
+
+unsafe extern "C" {
+    /// Number of apps, followed by the start and the end address of each app
+    static _num_app: usize;
+    /// NUL-terminated names of the apps
+    static _app_names: u8;
+}
+
+lazy_static! {
+    /// Name and ELF image of each app
+    static ref APPS: Vec<(&'static str, &'static [u8])> = {
+        let num_app = unsafe { _num_app };
+        let ranges = unsafe { slice::from_raw_parts((&raw const _num_app).add(1), num_app * 2) };
+        let mut name = &raw const _app_names;
+        ranges
+            .chunks_exact(2)
+            .map(|range| unsafe {
+                let app_name = CStr::from_ptr(name as *const _);
+                name = name.add(app_name.count_bytes() + 1);
+                let data = slice::from_raw_parts(range[0] as *const u8, range[1] - range[0]);
+                (app_name.to_str().expect("App name is not UTF-8"), data)
+            })
+            .collect()
+    };
+}
+
+/// Get the ELF image of the app `name`
+pub fn get_app_data_by_name(name: &str) -> Option<&'static [u8]> {
+    APPS.iter()
+        .find(|(app_name, _)| *app_name == name)
+        .map(|(_, data)| *data)
+}
@@ -0,0 +1,227 @@
+//! Machine information module
+//! The layout of the machine(memory, reserved ranges, devices and harts) is discovered from
+//! the device tree at boot, or falls back to the constants of the board if there is none
+
+use alloc::{string::String, vec::Vec};
+use core::ops::Range;
+
+use log::{info, warn};
+
+use crate::{
+    board::MMIO,
+    config::{MEMORY_END, PAGE_SIZE},
+    fdt::{Fdt, fdt_test},
+    sync::SpinLock,
+};
+
+/// Start of the physical memory of the qemu virt machine, used when there is no device tree
+const MEMORY_START: usize = 0x8000_0000;
+
+/// A memory-mapped device
+#[derive(Debug, Clone, Copy)]
+pub struct MmioDevice {
+    pub base: usize,
+    pub size: usize,
+    /// The interrupt source number at the PLIC
+    pub irq: Option<u32>,
+}
+
+impl MmioDevice {
+    /// The registers of the device, rounded to pages
+    pub fn range(&self) -> Range<usize> {
+        self.base & !(PAGE_SIZE - 1)..(self.base + self.size).next_multiple_of(PAGE_SIZE)
+    }
+}
+
+/// The layout of the machine
+#[derive(Debug, Clone, Default)]
+pub struct MachineInfo {
+    /// Physical memory regions
+    pub memory: Vec<Range<usize>>,
+    /// Memory which must not be used, from the memory reservation block and `/reserved-memory`
+    pub reserved: Vec<Range<usize>>,
+    /// The device tree blob itself
+    pub dtb: Option<Range<usize>>,
+    pub uart: Option<MmioDevice>,
+    pub plic: Option<MmioDevice>,
+    /// PLIC context of the S-mode external interrupt of each hart, as (hart, context)
+    pub plic_contexts: Vec<(usize, usize)>,
+    pub virtio: Vec<MmioDevice>,
+    /// Ids of the enabled harts
+    pub harts: Vec<usize>,
+    /// Kernel command line from `/chosen`
+    pub bootargs: String,
+    /// Frequency of the `time` register
+    pub timebase_frequency: Option<usize>,
+}
+
+lazy_static! {
+    /// The machine information, filled by `init`
+    static ref MACHINE: SpinLock<MachineInfo> = SpinLock::new(MachineInfo::default());
+}
+
+impl MachineInfo {
+    /// The layout used when there is no device tree
+    fn fallback() -> Self {
+        Self {
+            memory: alloc::vec![MEMORY_START..MEMORY_END],
+            virtio: MMIO
+                .iter()
+                .map(|&(base, size)| MmioDevice { base, size, irq: None })
+                .collect(),
+            harts: alloc::vec![0],
+            ..Self::default()
+        }
+    }
+
+    /// Collect the layout from a parsed device tree
+    fn from_fdt(fdt: &Fdt, dtb: usize) -> Self {
+        let mut machine = Self {
+            reserved: fdt.mem_reserved.clone(),
+            dtb: Some(dtb..dtb + fdt.total_size()),
+            ..Self::default()
+        };
+        for (index, node) in fdt.nodes.iter().enumerate() {
+            if !node.is_enabled() {
+                continue;
+            }
+            let parent = node.parent.map(|parent| fdt.nodes[parent].name);
+            let device = || {
+                let reg = fdt.reg(index);
+                let irq = node.prop("interrupts").and_then(|prop| prop.as_u32());
+                reg.first().map(|reg| MmioDevice {
+                    base: reg.start,
+                    size: reg.len(),
+                    irq,
+                })
+            };
+            if node.device_type() == Some("memory") {
+                machine.memory.extend(fdt.reg(index));
+            } else if parent == Some("reserved-memory") {
+                machine.reserved.extend(fdt.reg(index));
+            } else if node.device_type() == Some("cpu") {
+                if let Some(reg) = node.prop("reg").and_then(|prop| prop.as_u32()) {
+                    machine.harts.push(reg as usize);
+                }
+            } else if node.is_compatible("ns16550a") {
+                machine.uart = machine.uart.or_else(device);
+            } else if node.is_compatible("riscv,plic0") || node.is_compatible("sifive,plic-1.0.0") {
+                if machine.plic.is_none() {
+                    machine.plic = device();
+                    machine.plic_contexts = plic_contexts(fdt, index);
+                }
+            } else if node.is_compatible("virtio,mmio") {
+                machine.virtio.extend(device());
+            }
+        }
+        if let Some(cpus) = fdt.find_node("/cpus") {
+            machine.timebase_frequency = cpus
+                .prop("timebase-frequency")
+                .and_then(|prop| prop.as_u32())
+                .map(|freq| freq as usize);
+        }
+        if let Some(chosen) = fdt.find_node("/chosen") {
+            machine.bootargs = chosen
+                .prop("bootargs")
+                .and_then(|prop| prop.as_str())
+                .unwrap_or_default()
+                .into();
+        }
+        if machine.memory.is_empty() {
+            warn!("No memory node in the device tree");
+            machine.memory.push(MEMORY_START..MEMORY_END);
+        }
+        machine.memory.sort_by_key(|region| region.start);
+        machine.virtio.sort_by_key(|device| device.base);
+        machine.harts.sort();
+        machine
+    }
+
+    /// Memory free for the frame allocator: memory above `start`, without the reserved ranges and the device tree
+    pub fn usable_memory(&self, start: usize) -> Vec<Range<usize>> {
+        let mut usable: Vec<Range<usize>> = self
+            .memory
+            .iter()
+            .map(|region| region.start.max(start)..region.end)
+            .filter(|region| !region.is_empty())
+            .collect();
+        for hole in self.reserved.iter().chain(self.dtb.iter()) {
+            usable = usable
+                .into_iter()
+                .flat_map(|region| {
+                    [
+                        region.start..region.end.min(hole.start),
+                        region.start.max(hole.end)..region.end,
+                    ]
+                })
+                .filter(|region| !region.is_empty())
+                .collect();
+        }
+        usable
+    }
+
+    /// Registers of all devices, to be mapped in the kernel space
+    pub fn mmio_regions(&self) -> Vec<Range<usize>> {
+        self.uart
+            .iter()
+            .chain(self.plic.iter())
+            .chain(self.virtio.iter())
+            .map(MmioDevice::range)
+            .collect()
+    }
+}
+
+/// The S-mode external interrupt
+const IRQ_S_EXT: u32 = 9;
+
+/// Map the contexts of the PLIC at `index` to harts, `interrupts-extended` lists a
+/// (interrupt controller of a hart, interrupt) pair for each context
+fn plic_contexts(fdt: &Fdt, index: usize) -> Vec<(usize, usize)> {
+    let Some(prop) = fdt.nodes[index].prop("interrupts-extended") else {
+        return Vec::new();
+    };
+    let cells: Vec<u32> = prop.cells().collect();
+    cells
+        .chunks_exact(2)
+        .enumerate()
+        .filter(|(_, pair)| pair[1] == IRQ_S_EXT)
+        .filter_map(|(context, pair)| {
+            let intc = fdt.find_phandle(pair[0])?;
+            let cpu = &fdt.nodes[fdt.nodes[intc].parent?];
+            let hart = cpu.prop("reg")?.as_u32()? as usize;
+            Some((hart, context))
+        })
+        .collect()
+}
+
+/// Discover the machine from the device tree at `dtb`, the heap must be initialized
+pub fn init(dtb: usize) {
+    fdt_test();
+    let fdt = match dtb {
+        0 => None,
+        _ => unsafe { Fdt::from_ptr(dtb) }
+            .inspect_err(|err| warn!("Invalid device tree at {:#x}: {:?}", dtb, err))
+            .ok(),
+    };
+    let machine = match fdt {
+        Some(fdt) => MachineInfo::from_fdt(&fdt, dtb),
+        None => {
+            warn!("No device tree, using the default layout");
+            MachineInfo::fallback()
+        }
+    };
+    info!("Memory: {:x?}, reserved: {:x?}", machine.memory, machine.reserved);
+    info!(
+        "UART: {:x?}, PLIC: {:x?}, {} virtio devices",
+        machine.uart,
+        machine.plic,
+        machine.virtio.len()
+    );
+    info!("Harts: {:?}, bootargs: {:?}", machine.harts, machine.bootargs);
+    *MACHINE.lock() = machine;
+}
+
+/// Get a copy of the machine information
+pub fn machine_info() -> MachineInfo {
+    MACHINE.lock().clone()
+}
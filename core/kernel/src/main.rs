@@ -9,31 +9,86 @@ extern crate alloc;
 extern crate lazy_static;
 #[macro_use]
 mod console;
+mod block;
 #[path = "boards/qemu.rs"]
 mod board;
 mod config;
+mod cpu;
+mod drivers;
+mod fdt;
 mod lang_items;
+mod loader;
 mod logger;
+mod machine;
 pub mod memory;
 mod sbi;
 mod sync;
+mod syscall;
+mod task;
+mod timer;
+mod trap;
 
 use core::arch::global_asm;
 
 use log::info;
 
-global_asm!(include_str!("boot/entry.asm"));
+// the boot stack of a hart is found by shifting its id
+const _: () = assert!(config::BOOT_STACK_SIZE.is_power_of_two());
+
+global_asm!(
+    include_str!("boot/entry.asm"),
+    MAX_HARTS = const config::MAX_HARTS,
+    BOOT_STACK_SIZE = const config::BOOT_STACK_SIZE,
+    BOOT_STACK_SHIFT = const config::BOOT_STACK_SIZE.trailing_zeros(),
+);
 
 /// Entry point of kernel
+/// a0 is the id of the booting hart and a1 is the address of the device tree, passed by the SBI firmware
 #[unsafe(no_mangle)]
-pub fn rust_main() -> ! {
+pub fn rust_main(hartid: usize, dtb: usize) -> ! {
     clear_bss();
+    // before anything which asks for the boot hart, BOOT_HART is in .bss
+    cpu::set_boot_hart();
     logger::init();
+    sync::lock_test();
+    unsafe {
+        memory::init_heap();
+    }
+    machine::init(dtb);
+    drivers::plic::init();
+    drivers::uart::init();
     unsafe {
         memory::init();
     }
-    info!("Hello, world!");
-    panic!("shutdown");
+    drivers::virtio::init();
+    block::block_cache_test();
+    trap::init();
+    timer::init();
+    drivers::plic::init_hart();
+    info!("Hello, world! booting on hart {}", hartid);
+    task::add_initproc(loader::get_app_data_by_name("initproc").expect("No initproc in the kernel image"));
+    cpu::set_online();
+    cpu::start_secondary_harts();
+    task::run_tasks();
+}
+
+/// Entry point of the secondary harts, started by the boot hart after the kernel space is set up
+#[unsafe(no_mangle)]
+pub fn rust_main_secondary(hartid: usize) -> ! {
+    memory::KERNEL_SPACE.lock().activate();
+    trap::init();
+    timer::init_secondary();
+    drivers::plic::init_hart();
+    info!("Hart {} online", hartid);
+    cpu::set_online();
+    task::run_tasks();
+}
+
+/// Entry point of a boot hart whose id has no boot stack, it can't use anything indexed by the hartid
+#[unsafe(no_mangle)]
+pub fn rust_main_unsupported() -> ! {
+    sbi::legacy_write(b"[kernel] The boot hart id is too large, see MAX_HARTS in config.rs\n");
+    sbi::shutdown(true)
 }
 
 /// Clear the .bss section
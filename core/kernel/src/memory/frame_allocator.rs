@@ -5,9 +5,12 @@ use core::fmt::Debug;
 use log::info;
 
 use crate::{
-    config::MEMORY_END,
-    memory::address::{PhysAddr, PhysPageNum},
-    sync::safe_cell::*,
+    machine::machine_info,
+    memory::{
+        address::{PhysAddr, PhysPageNum},
+        kmem_cache_shrink_all,
+    },
+    sync::SpinLock,
 };
 
 /// Define the basic behavior of a frame allocator
@@ -36,8 +39,7 @@ impl BuddyFrameAllocator {
 }
 
 lazy_static! {
-    pub static ref FRAMEALLOCATOR: SafeCell<BuddyFrameAllocator> =
-        { unsafe { SafeCell::new(BuddyFrameAllocator::new()) } };
+    pub static ref FRAMEALLOCATOR: SpinLock<BuddyFrameAllocator> = SpinLock::new(BuddyFrameAllocator::new());
 }
 
 pub fn init_frame_allocator() {
@@ -48,11 +50,17 @@ pub fn init_frame_allocator() {
         #[allow(unused)]
         fn ekernel();
     }
-    // Initialize the frame allocator, frame available from ekernel to MEMORY_END
-    FRAMEALLOCATOR.exclusive_access().allocator.add_frame(
-        PhysAddr::from(ekernel as usize).ceil().0,
-        PhysAddr::from(MEMORY_END).floor().0,
-    );
+    // Initialize the frame allocator, frames available from ekernel to the end of each memory region
+    for region in machine_info().usable_memory(ekernel as usize) {
+        let (start, end) = (
+            PhysAddr::from(region.start).ceil().0,
+            PhysAddr::from(region.end).floor().0,
+        );
+        if start < end {
+            info!("Adding frames [{:#x}, {:#x})", region.start, region.end);
+            FRAMEALLOCATOR.lock().allocator.add_frame(start, end);
+        }
+    }
     frame_allocator_test();
 }
 
@@ -66,23 +74,39 @@ impl FrameAllocator for BuddyFrameAllocator {
     }
 }
 
+/// Allocate `num` contiguous frames, the empty slabs of the slab caches are released
+/// and it is tried again if there are not enough free frames
+fn alloc_or_shrink(num: usize) -> Result<PhysPageNum, AllocError> {
+    if let Some(ppn) = FRAMEALLOCATOR.lock().alloc(num) {
+        return Ok(ppn);
+    }
+    if kmem_cache_shrink_all() == 0 {
+        return Err(AllocError);
+    }
+    FRAMEALLOCATOR.lock().alloc(num).ok_or(AllocError)
+}
+
+/// Error of a failed allocation, there are not enough free frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+
 /// A structure to manage a single physframe
-#[derive(Debug, Clone)]
+/// it is shared by reference counting(Arc<Frame>), cloning it directly would free the frame twice
+#[derive(Debug)]
 pub struct Frame {
     pub ppn: PhysPageNum,
 }
 
 impl Frame {
-    pub fn alloc() -> Option<Self> {
-        let mut allocator = FRAMEALLOCATOR.exclusive_access();
-        let ppn = allocator.alloc(1).expect("Frame alloc fail: Out of memory");
-        Some(Self { ppn })
+    pub fn alloc() -> Result<Self, AllocError> {
+        let ppn = alloc_or_shrink(1)?;
+        Ok(Self { ppn })
     }
 }
 
 impl Drop for Frame {
     fn drop(&mut self) {
-        let mut allocator = FRAMEALLOCATOR.exclusive_access();
+        let mut allocator = FRAMEALLOCATOR.lock();
         allocator.dealloc(self.ppn, 1);
     }
 }
@@ -102,16 +126,26 @@ pub struct Frames {
 }
 
 impl Frames {
-    pub fn alloc(num: usize) -> Option<Self> {
-        let mut allocator = FRAMEALLOCATOR.exclusive_access();
-        let ppn = allocator.alloc(num).expect("Frame alloc fail: Out of memory");
+    pub fn alloc(num: usize) -> Result<Self, AllocError> {
+        let ppn = alloc_or_shrink(num)?;
+        Ok(Self { ppn, num })
+    }
+
+    /// Allocate frames without panicking, return None if there is no memory
+    /// or the frame allocator is in use on this hart, e.g. when the kernel heap grows inside it
+    pub fn try_alloc(num: usize) -> Option<Self> {
+        if FRAMEALLOCATOR.is_held_by_current_hart() {
+            return None;
+        }
+        let mut allocator = FRAMEALLOCATOR.lock();
+        let ppn = allocator.alloc(num)?;
         Some(Self { ppn, num })
     }
 }
 
 impl Drop for Frames {
     fn drop(&mut self) {
-        let mut allocator = FRAMEALLOCATOR.exclusive_access();
+        let mut allocator = FRAMEALLOCATOR.lock();
         allocator.dealloc(self.ppn, self.num);
     }
 }
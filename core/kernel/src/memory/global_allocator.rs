@@ -1,24 +1,161 @@
-use buddy_system_allocator::LockedHeap;
+//! Kernel heap allocator
+//! The heap starts with a static array in .bss, and grows with frames from the frame allocator
+//! when it runs out of memory. Frames given to the heap are never returned.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    hint::spin_loop,
+    mem,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use buddy_system_allocator::Heap;
 use log::info;
 
-use crate::config::KERNEL_HEAP_SIZE;
+use super::{address::PhysAddr, frame_allocator::Frames};
+use crate::{
+    config::{KERNEL_HEAP_GROW_SIZE, KERNEL_HEAP_SIZE, PAGE_SIZE},
+    cpu::hart_id,
+    sync::SpinLock,
+};
 
 /// Global allocator for the kernel heap.
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap<32> = LockedHeap::empty();
+static HEAP_ALLOCATOR: GrowingHeap = GrowingHeap::empty();
+
+/// The heap grows in advance when less free memory than this is left,
+/// the frame allocator itself needs some heap to allocate frames for the heap
+const HEAP_LOW_WATERMARK: usize = 0x4000;
+
+/// Value of `GrowingHeap::growing` when no hart is growing the heap
+const NOT_GROWING: usize = usize::MAX;
 
 /// Panic handler for heap allocation errors.
 #[alloc_error_handler]
 fn alloc_error_handler(layout: core::alloc::Layout) -> ! {
-    panic!("Heap allocation error: {:?}", layout);
+    panic!("Heap allocation error: {:?}, {:?}", layout, heap_stats());
 }
 
 static mut HEAP: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 
+/// Usage statistics of the kernel heap
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes managed by the heap, including the grown part
+    pub total_bytes: usize,
+    /// Bytes requested by allocations
+    pub user_bytes: usize,
+    /// Bytes actually taken by allocations, including the buddy rounding
+    pub allocated_bytes: usize,
+    /// Bytes taken from the frame allocator
+    pub grown_bytes: usize,
+}
+
+/// A buddy heap which adds frames to itself when it is exhausted
+struct GrowingHeap {
+    /// A kernel spin lock, so the heap is checked by lockdep like the other locks
+    heap: SpinLock<Heap<32>>,
+    /// Id of the hart which is growing the heap, allocations made by the frame allocator
+    /// on that hart then must not grow again, other harts wait for it
+    growing: AtomicUsize,
+    grown_bytes: AtomicUsize,
+}
+
+impl GrowingHeap {
+    const fn empty() -> Self {
+        Self {
+            heap: SpinLock::new(Heap::empty()),
+            growing: AtomicUsize::new(NOT_GROWING),
+            grown_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    fn free_bytes(&self) -> usize {
+        let heap = self.heap.lock();
+        heap.stats_total_bytes() - heap.stats_alloc_actual()
+    }
+
+    /// Add at least `min_bytes` of frames to the heap, return false if it can't
+    /// if another hart is growing the heap, wait for it and return true to retry
+    fn grow(&self, min_bytes: usize) -> bool {
+        let hart = hart_id();
+        if let Err(owner) = self
+            .growing
+            .compare_exchange(NOT_GROWING, hart, Ordering::Acquire, Ordering::Relaxed)
+        {
+            if owner == hart {
+                return false;
+            }
+            while self.growing.load(Ordering::Acquire) != NOT_GROWING {
+                spin_loop();
+            }
+            return true;
+        }
+        // a power of two of frames is aligned to its size, so the whole block goes into the heap
+        let pages = min_bytes
+            .max(KERNEL_HEAP_GROW_SIZE)
+            .div_ceil(PAGE_SIZE)
+            .next_power_of_two();
+        let grown = match Frames::try_alloc(pages) {
+            Some(frames) => {
+                // physical memory is mapped directly in the kernel space
+                let start = PhysAddr::from(frames.ppn).0;
+                let size = frames.num * PAGE_SIZE;
+                mem::forget(frames);
+                unsafe {
+                    self.heap.lock().add_to_heap(start, start + size);
+                }
+                self.grown_bytes.fetch_add(size, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        };
+        self.growing.store(NOT_GROWING, Ordering::Release);
+        grown
+    }
+}
+
+unsafe impl GlobalAlloc for GrowingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        loop {
+            let result = self.heap.lock().alloc(layout);
+            if let Ok(allocation) = result {
+                if self.free_bytes() < HEAP_LOW_WATERMARK {
+                    self.grow(KERNEL_HEAP_GROW_SIZE);
+                }
+                return allocation.as_ptr();
+            }
+            // the buddy allocator needs a block of the rounded size
+            if !self.grow(layout.size().max(layout.align()).next_power_of_two()) {
+                return ptr::null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.heap.lock().dealloc(NonNull::new_unchecked(ptr), layout) }
+    }
+}
+
+/// Get the usage statistics of the kernel heap
+pub fn heap_stats() -> HeapStats {
+    let heap = HEAP_ALLOCATOR.heap.lock();
+    HeapStats {
+        total_bytes: heap.stats_total_bytes(),
+        user_bytes: heap.stats_alloc_user(),
+        allocated_bytes: heap.stats_alloc_actual(),
+        grown_bytes: HEAP_ALLOCATOR.grown_bytes.load(Ordering::Relaxed),
+    }
+}
+
 #[allow(static_mut_refs)]
 pub unsafe fn init_heap() {
     unsafe {
-        HEAP_ALLOCATOR.lock().init(HEAP.as_ptr() as usize, KERNEL_HEAP_SIZE);
+        HEAP_ALLOCATOR
+            .heap
+            .lock()
+            .init(HEAP.as_ptr() as usize, KERNEL_HEAP_SIZE);
         test_heap();
     }
 }
@@ -47,3 +184,29 @@ fn test_heap() {
     drop(v);
     info!("Heap test passed!");
 }
+
+/// Fill the heap until it grows once from the frame allocator
+/// the frames given to the heap are never returned, so it stops right after the first growth
+#[allow(unused)]
+pub fn heap_grow_test() {
+    info!("Testing heap growth...");
+    use alloc::{vec, vec::Vec};
+    let HeapStats {
+        total_bytes,
+        grown_bytes: grown,
+        ..
+    } = heap_stats();
+    let mut chunks: Vec<Vec<u8>> = Vec::with_capacity(total_bytes / PAGE_SIZE);
+    while heap_stats().grown_bytes == grown {
+        assert!(chunks.len() < total_bytes / PAGE_SIZE, "The heap doesn't grow");
+        chunks.push(vec![chunks.len() as u8; PAGE_SIZE]);
+    }
+    for (i, chunk) in chunks.iter().enumerate() {
+        assert!(chunk.iter().all(|&b| b == i as u8));
+    }
+    let stats = heap_stats();
+    assert!(stats.grown_bytes - grown >= KERNEL_HEAP_GROW_SIZE);
+    assert!(stats.total_bytes > total_bytes);
+    drop(chunks);
+    info!("Heap growth test passed! {:?}", heap_stats());
+}
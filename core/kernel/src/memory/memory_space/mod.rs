@@ -1,21 +1,23 @@
 //! This is the memory space module.
 //! It contains MemorySpace assoicated items.
 
-use alloc::{collections::btree_map::BTreeMap, sync::Arc};
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec, vec::Vec};
 use core::{arch::asm, mem};
 
 use riscv::register::satp::{self, Satp};
 use vm_area::{MapPermission, VmArea};
-use xmas_elf::program;
+use xmas_elf::{header, program};
 
 use super::{
-    address::{PhysAddr, VirtAddr, VirtPageNum},
+    address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum},
+    frame_allocator::AllocError,
     page_table::{PTEFlags, PageTable},
 };
 use crate::{
-    board::MMIO,
-    config::{MEMORY_END, PAGE_SIZE, TRAMPOLINE},
-    sync::safe_cell::SafeCell,
+    config::{MMAP_BASE, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT, USER_SPACE_END, USER_STACK_SIZE},
+    machine::machine_info,
+    sync::SpinLock,
+    syscall::Errno,
 };
 pub mod vm_area;
 
@@ -36,16 +38,35 @@ unsafe extern "C" {
 
 lazy_static! {
     /// The kernel memory space
-    pub static ref KERNEL_SPACE: Arc<SafeCell<MemorySpace>> = {
-        unsafe {
-            Arc::new(SafeCell::new(MemorySpace::new_kernel()))
-        }
-    };
+    pub static ref KERNEL_SPACE: Arc<SpinLock<MemorySpace>> = Arc::new(SpinLock::new(
+        MemorySpace::new_kernel().expect("Failed to map the kernel space: Out of memory")
+    ));
 }
 
 /// Get the kernel memory space's satp token
 pub fn kernel_satp() -> usize {
-    KERNEL_SPACE.exclusive_access().page_table.satp_token()
+    KERNEL_SPACE.lock().page_table.satp_token()
+}
+
+/// Information of a loaded ELF image, it is passed to the program by the auxiliary vector
+#[derive(Debug, Clone, Copy)]
+pub struct ElfInfo {
+    /// Entry point of the program
+    pub entry: usize,
+    /// Virtual address of the program headers
+    pub phdr: usize,
+    /// Size of a program header
+    pub phent: usize,
+    /// Number of program headers
+    pub phnum: usize,
+}
+
+/// An area of an ELF image made of the segments which share pages, with the data of each segment
+struct LoadArea<'a> {
+    start: VirtAddr,
+    end: VirtAddr,
+    perm: MapPermission,
+    segments: Vec<(VirtAddr, &'a [u8])>,
 }
 
 /// Memory Space is the abstraction of a process's virtual memory.
@@ -53,15 +74,21 @@ pub fn kernel_satp() -> usize {
 pub struct MemorySpace {
     page_table: PageTable,
     areas: BTreeMap<VirtPageNum, VmArea>,
+    /// Start of the heap, just after the last loaded segment
+    heap_bottom: VirtPageNum,
+    /// Current program break, the heap area covers [heap_bottom, brk)
+    brk: usize,
 }
 
 impl MemorySpace {
     /// Create a new empty memory space
-    pub fn new_bare() -> Self {
-        Self {
-            page_table: PageTable::new(),
+    pub fn new_bare() -> Result<Self, AllocError> {
+        Ok(Self {
+            page_table: PageTable::new()?,
             areas: BTreeMap::new(),
-        }
+            heap_bottom: VirtPageNum(0),
+            brk: 0,
+        })
     }
 
     /// Get the satp token of the memory space
@@ -69,12 +96,210 @@ impl MemorySpace {
         self.page_table.satp_token()
     }
 
-    /// Assume that no conflict
-    // TODO! check the conflict
-    pub fn insert_framed_area(&mut self, start_va: VirtAddr, end_va: VirtAddr, perm: MapPermission) {
-        self.push(VmArea::new(start_va, end_va, vm_area::MapType::Framed, perm), None);
+    /// Translate the given virtual page number to the physical page number
+    pub fn translate(&self, vpn: VirtPageNum) -> Option<PhysPageNum> {
+        self.page_table.vpn2ppn(vpn)
+    }
+
+    /// Reserve a Framed area, its frames are allocated on the first access
+    /// return EEXIST if it overlaps an existing area
+    pub fn insert_framed_area(
+        &mut self, start_va: VirtAddr, end_va: VirtAddr, perm: MapPermission,
+    ) -> Result<(), Errno> {
+        if !self.is_free(start_va.floor(), end_va.ceil()) {
+            return Err(Errno::EEXIST);
+        }
+        self.push(VmArea::new(start_va, end_va, vm_area::MapType::Framed, perm), None)?;
+        Ok(())
+    }
+
+    /// Insert a Framed area with all its frames allocated now, for areas which can't take a page fault
+    /// the kernel places these areas itself, so an overlap is a bug
+    pub fn insert_populated_area(
+        &mut self, start_va: VirtAddr, end_va: VirtAddr, perm: MapPermission,
+    ) -> Result<(), AllocError> {
+        assert!(
+            self.is_free(start_va.floor(), end_va.ceil()),
+            "Populated area overlaps an existing area"
+        );
+        self.push_populated(VmArea::new(start_va, end_va, vm_area::MapType::Framed, perm))
+    }
+
+    /// Check if [start_vpn, end_vpn) doesn't overlap any area
+    fn is_free(&self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> bool {
+        // areas don't overlap, so only the last one starting below end_vpn can reach start_vpn
+        self.areas
+            .range(..end_vpn)
+            .next_back()
+            .is_none_or(|(_, area)| area.end_vpn() <= start_vpn)
+    }
+
+    /// Find the lowest free range of `pages` pages at or above `from`, below USER_SPACE_END
+    fn find_free_range(&self, from: VirtPageNum, pages: usize) -> Option<VirtPageNum> {
+        let mut start = from.0;
+        for area in self.areas.values() {
+            if area.end_vpn().0 <= start {
+                continue;
+            }
+            if area.start_vpn().0 >= start + pages {
+                break;
+            }
+            start = area.end_vpn().0;
+        }
+        let end_vpn: VirtPageNum = VirtAddr::from(USER_SPACE_END).floor();
+        (start + pages <= end_vpn.0).then_some(VirtPageNum(start))
+    }
+
+    /// Map an anonymous area of `len` bytes with `perm`, return its start address
+    /// `hint` is used if the range is free, or the exact address if `fixed`, in which case
+    /// the old mappings there are replaced; otherwise a free range above MMAP_BASE is picked
+    pub fn mmap(&mut self, hint: usize, len: usize, perm: MapPermission, fixed: bool) -> Result<usize, Errno> {
+        let pages = len.div_ceil(PAGE_SIZE);
+        let hint_vpn = VirtAddr::from(hint).floor();
+        let hint_end_vpn = VirtPageNum(hint_vpn.0 + pages);
+        let start_vpn = if fixed {
+            self.munmap(hint_vpn, hint_end_vpn);
+            hint_vpn
+        } else if hint != 0 && self.is_free(hint_vpn, hint_end_vpn) {
+            hint_vpn
+        } else {
+            self.find_free_range(VirtAddr::from(MMAP_BASE).floor(), pages)
+                .ok_or(Errno::ENOMEM)?
+        };
+        let start_va: VirtAddr = start_vpn.into();
+        let end_va: VirtAddr = VirtPageNum(start_vpn.0 + pages).into();
+        self.insert_framed_area(start_va, end_va, perm)?;
+        Ok(start_va.into())
+    }
+
+    /// Move the program break to `new_brk`, return the new break
+    /// the heap area is extended in place when it grows and its pages are freed when it shrinks,
+    /// the break is not changed if it would go below the heap bottom or run into another area
+    pub fn brk(&mut self, new_brk: usize) -> usize {
+        let heap_bottom_va: VirtAddr = self.heap_bottom.into();
+        if new_brk < heap_bottom_va.into() || new_brk > USER_SPACE_END {
+            return self.brk;
+        }
+        let old_end_vpn = VirtAddr::from(self.brk).ceil();
+        let new_end_vpn = VirtAddr::from(new_brk).ceil();
+        if new_end_vpn > old_end_vpn {
+            if !self.is_free(old_end_vpn, new_end_vpn) {
+                return self.brk;
+            }
+            match self.areas.get_mut(&self.heap_bottom) {
+                // the frames of the new pages are allocated on the first access
+                Some(heap) if heap.end_vpn() == old_end_vpn => heap.extend_to(new_end_vpn),
+                // no heap area yet, or a part of it has been unmapped
+                _ => {
+                    let perm = MapPermission::R | MapPermission::W | MapPermission::U;
+                    if self
+                        .insert_framed_area(old_end_vpn.into(), new_end_vpn.into(), perm)
+                        .is_err()
+                    {
+                        return self.brk;
+                    }
+                }
+            }
+        } else if new_end_vpn < old_end_vpn {
+            self.munmap(new_end_vpn, old_end_vpn);
+        }
+        self.brk = new_brk;
+        self.brk
+    }
+
+    /// Take [start_vpn, end_vpn) out of the areas overlapping it, partially covered areas are split
+    /// the removed pieces are returned in address order
+    fn take_range(&mut self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> Vec<VmArea> {
+        let keys: Vec<VirtPageNum> = self
+            .areas
+            .range(..end_vpn)
+            .filter(|(_, area)| area.end_vpn() > start_vpn)
+            .map(|(&key, _)| key)
+            .collect();
+        let mut taken = Vec::with_capacity(keys.len());
+        for key in keys {
+            let mut area = self.areas.remove(&key).unwrap();
+            if area.start_vpn() < start_vpn {
+                let tail = area.split_off(start_vpn);
+                self.areas.insert(area.start_vpn(), area);
+                area = tail;
+            }
+            if area.end_vpn() > end_vpn {
+                let rest = area.split_off(end_vpn);
+                self.areas.insert(rest.start_vpn(), rest);
+            }
+            taken.push(area);
+        }
+        taken
+    }
+
+    /// Unmap [start_vpn, end_vpn), the range may cover several areas or parts of them
+    pub fn munmap(&mut self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) {
+        for mut area in self.take_range(start_vpn, end_vpn) {
+            area.unmap(&mut self.page_table);
+        }
+    }
+
+    /// Change the permission of [start_vpn, end_vpn) to `perm`
+    /// return ENOMEM if any page in the range is not mapped, or the page tables can't be allocated
+    pub fn mprotect(&mut self, start_vpn: VirtPageNum, end_vpn: VirtPageNum, perm: MapPermission) -> Result<(), Errno> {
+        let mut covered = start_vpn;
+        for area in self.areas.range(..end_vpn).map(|(_, area)| area) {
+            if area.end_vpn() <= covered {
+                continue;
+            }
+            if area.start_vpn() > covered {
+                return Err(Errno::ENOMEM);
+            }
+            covered = area.end_vpn();
+        }
+        if covered < end_vpn {
+            return Err(Errno::ENOMEM);
+        }
+        let mut result = Ok(());
+        for mut area in self.take_range(start_vpn, end_vpn) {
+            if result.is_ok() {
+                result = area.set_perm(&mut self.page_table, perm);
+            }
+            self.areas.insert(area.start_vpn(), area);
+        }
+        Ok(result?)
+    }
+
+    /// Find the vm area which contains the given virtual page number
+    pub fn find_area(&self, vpn: VirtPageNum) -> Option<&VmArea> {
+        self.areas
+            .range(..=vpn)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.contains(vpn))
+    }
+
+    /// Handle a user page fault at `vpn` which requires `access`(R/W/X)
+    /// return Ok(false) if it is a real fault, e.g. a write to a read-only area,
+    /// or AllocError if the page is valid but there is no memory for it
+    pub fn handle_page_fault(&mut self, vpn: VirtPageNum, access: PTEFlags) -> Result<bool, AllocError> {
+        // can't use find_area here, the page table is borrowed at the same time
+        let area = self.areas.range_mut(..=vpn).next_back().map(|(_, area)| area);
+        let Some(area) = area.filter(|area| area.contains(vpn)) else {
+            return Ok(false);
+        };
+        area.handle_fault(&mut self.page_table, vpn, access)
+    }
+
+    /// Translate a user page which the kernel is going to access on behalf of U-mode
+    /// a page which is not filled yet or shared copy-on-write is resolved as a page fault first
+    pub fn translate_user(&mut self, vpn: VirtPageNum, access: PTEFlags) -> Result<PhysPageNum, Errno> {
+        self.page_table.translate_user(vpn, access).or_else(|_| {
+            if self.handle_page_fault(vpn, access)? {
+                self.page_table.translate_user(vpn, access)
+            } else {
+                Err(Errno::EFAULT)
+            }
+        })
     }
 
+    /// Remove the area starting at `start_vpn`
     pub fn remove_area_with_start_vpn(&mut self, start_vpn: VirtPageNum) {
         let area = self.areas.remove(&start_vpn);
         if let Some(mut area) = area {
@@ -82,32 +307,57 @@ impl MemorySpace {
         }
     }
 
-    /// Push a new vm area into the memory space
-    fn push(&mut self, mut vm_area: VmArea, data: Option<&[u8]>) {
-        VmArea::map(&mut vm_area, &mut self.page_table);
-        if let Some(data) = data {
-            vm_area.copy_data(&mut self.page_table, data);
+    /// Push a new vm area into the memory space, `data` is copied to the start of the area
+    /// nothing is left mapped if it fails
+    fn push(&mut self, vm_area: VmArea, data: Option<&[u8]>) -> Result<(), AllocError> {
+        let start_va = vm_area.start_vpn().into();
+        self.push_segments(vm_area, data.map(|data| (start_va, data)).as_slice())
+    }
+
+    /// Push a new vm area into the memory space and copy each segment to its address in the area
+    /// nothing is left mapped if it fails
+    fn push_segments(&mut self, mut vm_area: VmArea, segments: &[(VirtAddr, &[u8])]) -> Result<(), AllocError> {
+        let result = VmArea::map(&mut vm_area, &mut self.page_table).and_then(|_| {
+            segments
+                .iter()
+                .try_for_each(|&(start_va, data)| vm_area.copy_data(&mut self.page_table, start_va, data))
+        });
+        if let Err(err) = result {
+            vm_area.unmap(&mut self.page_table);
+            return Err(err);
         }
         self.areas.insert(vm_area.start_vpn().clone(), vm_area);
+        Ok(())
+    }
+
+    /// Push a new vm area into the memory space with all its pages mapped
+    /// nothing is left mapped if it fails
+    fn push_populated(&mut self, mut vm_area: VmArea) -> Result<(), AllocError> {
+        if let Err(err) = vm_area.populate(&mut self.page_table) {
+            vm_area.unmap(&mut self.page_table);
+            return Err(err);
+        }
+        self.areas.insert(vm_area.start_vpn(), vm_area);
+        Ok(())
     }
 
     /// Map the Trampoline page at the top of the kernel memory space
     /// The trampoline page is used to switch between kernel and user space
     /// Mention that the trampoline page is not collected by areas
-    pub fn map_trampoline(&mut self) {
+    pub fn map_trampoline(&mut self) -> Result<(), AllocError> {
         self.page_table.map(
             VirtAddr::from(TRAMPOLINE).into(),
             PhysAddr::from(strampoline as usize).into(),
             PTEFlags::R | PTEFlags::X,
-        );
+        )
     }
 
     /// Create kernel memory space
-    pub fn new_kernel() -> Self {
-        let mut kernel_space = Self::new_bare();
+    pub fn new_kernel() -> Result<Self, AllocError> {
+        let mut kernel_space = Self::new_bare()?;
 
         // Map trampoline page
-        kernel_space.map_trampoline();
+        kernel_space.map_trampoline()?;
 
         // Map kernel sections
         println!(".text [{:#x}, {:#x}]", stext as usize, etext as usize);
@@ -124,7 +374,7 @@ impl MemorySpace {
                 MapPermission::R | MapPermission::X,
             ),
             None,
-        );
+        )?;
         println!("mapping rodata section");
         kernel_space.push(
             VmArea::new(
@@ -134,7 +384,7 @@ impl MemorySpace {
                 MapPermission::R,
             ),
             None,
-        );
+        )?;
 
         println!("mapping data section");
         kernel_space.push(
@@ -145,7 +395,7 @@ impl MemorySpace {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
+        )?;
         println!("mapping stack section");
         kernel_space.push(
             VmArea::new(
@@ -155,7 +405,7 @@ impl MemorySpace {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
+        )?;
         println!("mapping bss section");
         kernel_space.push(
             VmArea::new(
@@ -165,51 +415,85 @@ impl MemorySpace {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
+        )?;
         // Map whole physical memory make the kernel can access all physical memory directly
+        let machine = machine_info();
         println!("mapping physical memory");
-        kernel_space.push(
-            VmArea::new(
-                VirtAddr::from(ekernel as usize),
-                VirtAddr::from(MEMORY_END),
-                vm_area::MapType::Direct,
-                MapPermission::R | MapPermission::W,
-            ),
-            None,
-        );
+        for region in &machine.memory {
+            let start = region.start.max(ekernel as usize);
+            if start < region.end {
+                kernel_space.push(
+                    VmArea::new(
+                        VirtAddr::from(start),
+                        VirtAddr::from(region.end),
+                        vm_area::MapType::Direct,
+                        MapPermission::R | MapPermission::W,
+                    ),
+                    None,
+                )?;
+            }
+        }
         println!("mapping memory-mapped registers");
-        for pair in MMIO {
+        for region in machine.mmio_regions() {
             kernel_space.push(
                 VmArea::new(
-                    VirtAddr::from((*pair).0),
-                    VirtAddr::from((*pair).0 + (*pair).1),
+                    VirtAddr::from(region.start),
+                    VirtAddr::from(region.end),
                     vm_area::MapType::Direct,
                     MapPermission::R | MapPermission::W,
                 ),
                 None,
-            );
+            )?;
         }
-        kernel_space
+        Ok(kernel_space)
     }
 
-    /// Map content from elf file to the memory space
-    /// returns user_sp_base and entry point
-    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
-        let mut memory_set = Self::new_bare();
+    /// Map content from elf file to the memory space, the heap starts just after the last segment
+    /// and the user stack is mapped at the top of user space
+    /// returns the top of the user stack and the information of the ELF image
+    /// ENOEXEC if it isn't a valid RISC-V ELF64 executable, the image itself must be 8-byte aligned
+    pub fn from_elf(elf_data: &[u8]) -> Result<(Self, usize, ElfInfo), Errno> {
+        let elf = xmas_elf::ElfFile::new(elf_data).map_err(|_| Errno::ENOEXEC)?;
+        let elf_header = elf.header;
+        let ph_offset = elf_header.pt2.ph_offset() as usize;
+        // xmas_elf panics on program headers out of the image and on unaligned reads
+        let ph_size = size_of::<program::ProgramHeader64>();
+        let ph_end = ph_offset.checked_add(elf_header.pt2.ph_count() as usize * ph_size);
+        if elf_header.pt1.class() != header::Class::SixtyFour
+            || elf_header.pt2.machine().as_machine() != header::Machine::RISC_V
+            || elf_header.pt2.ph_entry_size() as usize != ph_size
+            || ph_end.is_none_or(|end| end > elf_data.len())
+            || !(elf_data.as_ptr() as usize + ph_offset).is_multiple_of(align_of::<u64>())
+        {
+            return Err(Errno::ENOEXEC);
+        }
+        let mut memory_set = Self::new_bare()?;
         // map the trampoline page
-        memory_set.map_trampoline();
+        memory_set.map_trampoline()?;
         // map program content with U flag
-        let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
-        let elf_header = elf.header;
-        let magic = elf_header.pt1.magic;
-        assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "Invalid ELF file");
-        let mut max_end_vpn = VirtPageNum(0);
-        elf.program_iter().for_each(|ph| {
+        let mut phdr = 0;
+        let mut max_end = 0;
+        let mut areas: Vec<LoadArea> = Vec::new();
+        for ph in elf.program_iter() {
             if let Ok(p_type) = ph.get_type() {
                 match p_type {
                     xmas_elf::program::Type::Load => {
-                        let start_va: VirtAddr = (ph.virtual_addr() as usize).into();
-                        let end_va: VirtAddr = ((ph.virtual_addr() + ph.mem_size()) as usize).into();
+                        // segments are sorted by address, they must not overlap each other or the user stack
+                        // but a segment may start on the page where the previous one ends
+                        let (offset, file_size) = (ph.offset() as usize, ph.file_size() as usize);
+                        let (vaddr, mem_size) = (ph.virtual_addr() as usize, ph.mem_size() as usize);
+                        let end = vaddr
+                            .checked_add(mem_size)
+                            .filter(|&end| end <= USER_SPACE_END - USER_STACK_SIZE);
+                        let data = offset.checked_add(file_size).and_then(|end| elf.input.get(offset..end));
+                        let (Some(end), Some(data)) = (end, data) else {
+                            return Err(Errno::ENOEXEC);
+                        };
+                        if file_size > mem_size || vaddr < max_end {
+                            return Err(Errno::ENOEXEC);
+                        }
+                        let start_va: VirtAddr = vaddr.into();
+                        let end_va: VirtAddr = end.into();
                         let mut map_perm = MapPermission::U;
                         let ph_flags = ph.flags();
                         if ph_flags.is_read() {
@@ -221,41 +505,85 @@ impl MemorySpace {
                         if ph_flags.is_execute() {
                             map_perm |= MapPermission::X;
                         }
-                        let area = VmArea::new(start_va, end_va, vm_area::MapType::Framed, map_perm);
-                        max_end_vpn = area.end_vpn();
-                        memory_set.push(
-                            area,
-                            Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
-                        );
+                        // the program headers are loaded with the segment which contains them
+                        if (offset..offset + file_size).contains(&ph_offset) {
+                            phdr = vaddr + ph_offset - offset;
+                        }
+                        max_end = end;
+                        match areas.last_mut() {
+                            // segments sharing a page share an area, with the permissions of both
+                            Some(area) if start_va.floor() < area.end.ceil() => {
+                                area.end = end_va;
+                                area.perm |= map_perm;
+                                area.segments.push((start_va, data));
+                            }
+                            _ => areas.push(LoadArea {
+                                start: start_va,
+                                end: end_va,
+                                perm: map_perm,
+                                segments: vec![(start_va, data)],
+                            }),
+                        }
                     }
                     _ => {}
                 }
             }
-        });
-        let max_end_va: VirtAddr = max_end_vpn.into();
-        let mut user_stack_base: usize = max_end_va.into();
-        user_stack_base += PAGE_SIZE;
-
-        (memory_set, user_stack_base, elf.header.pt2.entry_point() as usize)
+        }
+        for area in areas {
+            let load_area = VmArea::new(area.start, area.end, vm_area::MapType::Framed, area.perm);
+            memory_set.push_segments(load_area, &area.segments)?;
+        }
+        let max_end_vpn = VirtAddr::from(max_end).ceil();
+        // map the trap context page, it is only accessible in S-mode
+        memory_set.push_populated(VmArea::new(
+            TRAP_CONTEXT.into(),
+            TRAMPOLINE.into(),
+            vm_area::MapType::Framed,
+            MapPermission::R | MapPermission::W,
+        ))?;
+        // the heap is empty until the first brk
+        memory_set.heap_bottom = max_end_vpn;
+        memory_set.brk = VirtAddr::from(max_end_vpn).into();
+        // map the user stack at the top of user space, so the heap can grow towards it
+        let user_sp = USER_SPACE_END;
+        let user_stack_base = user_sp - USER_STACK_SIZE;
+        memory_set.push(
+            VmArea::new(
+                user_stack_base.into(),
+                user_sp.into(),
+                vm_area::MapType::Framed,
+                MapPermission::R | MapPermission::W | MapPermission::U,
+            ),
+            None,
+        )?;
+        let elf_info = ElfInfo {
+            entry: elf_header.pt2.entry_point() as usize,
+            phdr,
+            phent: elf_header.pt2.ph_entry_size() as usize,
+            phnum: elf_header.pt2.ph_count() as usize,
+        };
+        Ok((memory_set, user_sp, elf_info))
     }
 
-    /// Create a new memory space from an existed user space
-    /// Copy the data sections, user stack and trap context
-    pub fn from_existed_user(user_space: &MemorySpace) -> Self {
-        let mut memory_set = Self::new_bare();
+    /// Create a new memory space from an existed user space for fork
+    /// The frames are shared copy-on-write, only the trap context is copied
+    pub fn from_existed_user(user_space: &mut MemorySpace) -> Result<Self, AllocError> {
+        let mut memory_set = Self::new_bare()?;
         // map the trampoline page
-        memory_set.map_trampoline();
-        // copy data sections/trap_context/user_stack
+        memory_set.map_trampoline()?;
+        memory_set.heap_bottom = user_space.heap_bottom;
+        memory_set.brk = user_space.brk;
+        let trap_cx_vpn: VirtPageNum = VirtAddr::from(TRAP_CONTEXT).into();
         for area in user_space.areas.values() {
-            let new_area = VmArea::from_another(area);
-            memory_set.push(new_area, None);
-            for vpn in area.start_vpn()..area.end_vpn() {
-                let src_ppn = user_space.page_table.vpn2ppn(vpn).unwrap();
-                let dst_ppn = memory_set.page_table.vpn2ppn(vpn).unwrap();
-                dst_ppn.get_bytes_mut().copy_from_slice(&src_ppn.get_bytes_mut());
-            }
+            let new_area = if area.start_vpn() == trap_cx_vpn {
+                // the kernel writes the trap context through its physical address, which bypasses COW
+                area.duplicate(&mut memory_set.page_table)?
+            } else {
+                area.share_cow(&mut user_space.page_table, &mut memory_set.page_table)?
+            };
+            memory_set.areas.insert(new_area.start_vpn(), new_area);
         }
-        memory_set
+        Ok(memory_set)
     }
 
     /// Activate current memory space
@@ -273,17 +601,19 @@ impl MemorySpace {
     }
 
     pub fn kernel_copy() -> Self {
-        let areas = KERNEL_SPACE.exclusive_access().areas.clone();
+        let areas = KERNEL_SPACE.lock().areas.clone();
         Self {
             page_table: PageTable::from_satp(kernel_satp()),
             areas: areas,
+            heap_bottom: VirtPageNum(0),
+            brk: 0,
         }
     }
 }
 
 #[allow(unused)]
 pub fn remap_test() {
-    let mut kernel_space = KERNEL_SPACE.exclusive_access();
+    let mut kernel_space = KERNEL_SPACE.lock();
     let mid_text: VirtAddr = ((stext as usize + etext as usize) / 2).into();
     let mid_rodata: VirtAddr = ((srodata as usize + erodata as usize) / 2).into();
     let mid_data: VirtAddr = ((sdata as usize + edata as usize) / 2).into();
@@ -301,3 +631,66 @@ pub fn remap_test() {
     );
     println!("remap_test passed!");
 }
+
+/// Map, split and unmap anonymous areas in a bare memory space
+pub fn mmap_test() {
+    let mut space = MemorySpace::new_bare().unwrap();
+    let rw = MapPermission::R | MapPermission::W | MapPermission::U;
+    let start = space.mmap(0, 4 * PAGE_SIZE, rw, false).unwrap();
+    assert_eq!(start, MMAP_BASE);
+    let start_vpn = VirtAddr::from(start).floor();
+    let vpn = |i: usize| VirtPageNum(start_vpn.0 + i);
+    // a hinted range which overlaps is moved to the next free range
+    assert_eq!(space.mmap(start, PAGE_SIZE, rw, false).unwrap(), start + 4 * PAGE_SIZE);
+    assert!(space.handle_page_fault(vpn(1), PTEFlags::W).unwrap());
+    // punch a hole in the middle, the area is split in two
+    space.munmap(vpn(1), vpn(2));
+    assert_eq!(space.areas.len(), 3);
+    assert!(space.find_area(vpn(1)).is_none());
+    assert!(space.translate(vpn(1)).is_none());
+    // mprotect over the hole fails, then turns the tail read-only
+    assert_eq!(space.mprotect(vpn(0), vpn(3), rw), Err(Errno::ENOMEM));
+    space
+        .mprotect(vpn(2), vpn(4), MapPermission::R | MapPermission::U)
+        .unwrap();
+    assert!(!space.handle_page_fault(vpn(3), PTEFlags::W).unwrap());
+    assert!(space.handle_page_fault(vpn(3), PTEFlags::R).unwrap());
+    // the hole can be mapped again at the exact address
+    assert_eq!(
+        space.mmap(start + PAGE_SIZE, PAGE_SIZE, rw, true).unwrap(),
+        start + PAGE_SIZE
+    );
+    space.munmap(vpn(0), vpn(5));
+    assert!(space.areas.is_empty());
+    println!("mmap_test passed!");
+}
+
+/// Grow and shrink the heap of a bare memory space
+pub fn brk_test() {
+    let mut space = MemorySpace::new_bare().unwrap();
+    space.heap_bottom = VirtAddr::from(MMAP_BASE).floor();
+    space.brk = MMAP_BASE;
+    // can't go below the heap bottom
+    assert_eq!(space.brk(MMAP_BASE - 1), MMAP_BASE);
+    assert_eq!(space.brk(MMAP_BASE + 10), MMAP_BASE + 10);
+    assert_eq!(space.brk(MMAP_BASE + 3 * PAGE_SIZE), MMAP_BASE + 3 * PAGE_SIZE);
+    // the heap is extended in place
+    assert_eq!(space.areas.len(), 1);
+    let last_vpn = VirtPageNum(space.heap_bottom.0 + 2);
+    assert!(space.handle_page_fault(last_vpn, PTEFlags::W).unwrap());
+    // shrinking frees the pages above the break
+    space.brk(MMAP_BASE + PAGE_SIZE);
+    assert!(space.translate(last_vpn).is_none());
+    assert!(!space.handle_page_fault(last_vpn, PTEFlags::W).unwrap());
+    // the heap can't run into another area
+    space
+        .mmap(
+            MMAP_BASE + 2 * PAGE_SIZE,
+            PAGE_SIZE,
+            MapPermission::R | MapPermission::U,
+            true,
+        )
+        .unwrap();
+    assert_eq!(space.brk(MMAP_BASE + 4 * PAGE_SIZE), MMAP_BASE + PAGE_SIZE);
+    println!("brk_test passed!");
+}
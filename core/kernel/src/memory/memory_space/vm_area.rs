@@ -1,8 +1,11 @@
 //! This is virtual memory area module.
 //! It defines the VmArea structure and function to manage it.
 
-use alloc::collections::btree_map::BTreeMap;
-use core::ops::Range;
+use alloc::{collections::btree_map::BTreeMap, sync::Arc};
+use core::{
+    fmt::{self, Debug},
+    ops::Range,
+};
 
 use bitflags::bitflags;
 
@@ -10,14 +13,14 @@ use crate::{
     config::PAGE_SIZE,
     memory::{
         address::{PhysPageNum, VirtAddr, VirtPageNum},
-        frame_allocator::Frame,
-        page_table::{self, PTEFlags, PageTable},
+        frame_allocator::{AllocError, Frame},
+        page_table::{self, PTEFlags, PageTable, PageTableEntry},
     },
 };
 
 bitflags! {
     /// Map permission flags
-    #[derive(Clone, PartialEq, Eq)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
     pub struct MapPermission: u8 {
         const R = 1 << 1;
         const W = 1 << 2;
@@ -36,10 +39,12 @@ pub enum MapType {
 }
 
 /// Virtual memory area
+/// frames are reference counted, so they can be shared copy-on-write between forked spaces
+/// Framed areas are filled lazily, a page gets its frame on the first page fault
 #[derive(Clone)]
 pub struct VmArea {
     vpns: Range<VirtPageNum>,
-    frames_map: BTreeMap<VirtPageNum, Frame>,
+    frames_map: BTreeMap<VirtPageNum, Arc<Frame>>,
     perm: MapPermission,
     map_type: MapType,
 }
@@ -64,34 +69,119 @@ impl VmArea {
         self.vpns.end
     }
 
+    /// Check if the given virtual page number is in this area
+    pub fn contains(&self, vpn: VirtPageNum) -> bool {
+        self.vpns.contains(&vpn)
+    }
+
+    pub fn perm(&self) -> MapPermission {
+        self.perm
+    }
+
+    /// Check if the pages can be accessed at all, PROT_NONE pages have no PTE
+    /// (a valid PTE without R/W/X would point to the next level page table)
+    fn is_accessible(&self) -> bool {
+        self.perm
+            .intersects(MapPermission::R | MapPermission::W | MapPermission::X)
+    }
+
+    /// Split the area at `at`, this area keeps [start, at) and the returned one holds [at, end)
+    pub fn split_off(&mut self, at: VirtPageNum) -> Self {
+        debug_assert!(
+            self.vpns.start < at && at < self.vpns.end,
+            "Split point out of the area"
+        );
+        let tail = Self {
+            vpns: at..self.vpns.end,
+            frames_map: self.frames_map.split_off(&at),
+            perm: self.perm,
+            map_type: self.map_type,
+        };
+        self.vpns.end = at;
+        tail
+    }
+
+    /// Extend the end of a Framed area to `end_vpn`, the new pages are filled lazily
+    pub fn extend_to(&mut self, end_vpn: VirtPageNum) {
+        debug_assert!(self.map_type == MapType::Framed && end_vpn >= self.vpns.end);
+        self.vpns.end = end_vpn;
+    }
+
+    /// Change the permission of the area and rewrite the PTEs of the mapped pages
+    /// pages shared copy-on-write stay read-only until they are written
+    pub fn set_perm(&mut self, page_table: &mut PageTable, perm: MapPermission) -> Result<(), AllocError> {
+        self.perm = perm;
+        let flags = self.pte_flags() | PTEFlags::V;
+        let accessible = self.is_accessible();
+        for (&vpn, frame) in self.frames_map.iter() {
+            // the PTE of a PROT_NONE page may be gone, but its page table still exists
+            let pte = page_table.find_pte_or_create(vpn)?;
+            *pte = if !accessible {
+                PageTableEntry::empty()
+            } else if Arc::strong_count(frame) > 1 {
+                PageTableEntry::new(frame.ppn, flags - PTEFlags::W)
+            } else {
+                PageTableEntry::new(frame.ppn, flags)
+            };
+        }
+        Ok(())
+    }
+
     pub fn from_another(another: &VmArea) -> Self {
         Self {
             vpns: another.vpns.clone(),
             frames_map: BTreeMap::new(),
-            perm: another.perm.clone(),
-            map_type: another.map_type.clone(),
+            perm: another.perm,
+            map_type: another.map_type,
         }
     }
 
-    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
-        let ppn: PhysPageNum;
-        match self.map_type {
-            MapType::Direct => {
-                ppn = PhysPageNum(vpn.0);
-            }
+    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> Result<(), AllocError> {
+        let ppn = match self.map_type {
+            MapType::Direct => PhysPageNum(vpn.0),
             MapType::Framed => {
-                let frame = Frame::alloc().expect("Frame alloc fail: Out of memory");
-                ppn = frame.ppn;
-                self.frames_map.insert(vpn, frame);
+                let frame = Frame::alloc()?;
+                // the frame may hold data of another space
+                frame.ppn.get_bytes_mut().fill(0);
+                // map first, the frame is freed if the page table can't be allocated
+                page_table.map(vpn, frame.ppn, self.pte_flags())?;
+                self.frames_map.insert(vpn, Arc::new(frame));
+                return Ok(());
             }
+        };
+        page_table.map(vpn, ppn, self.pte_flags())
+    }
+
+    /// PTE flags of the pages in this area
+    fn pte_flags(&self) -> PTEFlags {
+        PTEFlags::from_bits(self.perm.bits()).unwrap()
+    }
+
+    /// Map the area into the page table
+    /// Direct areas are mapped at once, Framed areas are only reserved and filled by page faults
+    pub fn map(&mut self, page_table: &mut PageTable) -> Result<(), AllocError> {
+        if self.map_type == MapType::Direct {
+            self.populate(page_table)?;
         }
-        let pte_flags = PTEFlags::from_bits(self.perm.bits()).unwrap();
-        page_table.map(vpn, ppn, pte_flags);
+        Ok(())
     }
 
-    pub fn map(&mut self, page_table: &mut PageTable) {
+    /// Map every page of the area which hasn't been mapped yet
+    /// used by areas which can't take a page fault, e.g. kernel stacks and the trap context
+    pub fn populate(&mut self, page_table: &mut PageTable) -> Result<(), AllocError> {
         for vpn in self.vpns.clone() {
-            self.map_one(page_table, vpn);
+            if !self.is_mapped(vpn) {
+                self.map_one(page_table, vpn)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if the page has been mapped, Direct pages are always mapped
+    fn is_mapped(&self, vpn: VirtPageNum) -> bool {
+        match self.map_type {
+            MapType::Direct => true,
+            MapType::Framed => self.frames_map.contains_key(&vpn),
         }
     }
 
@@ -99,7 +189,10 @@ impl VmArea {
         match self.map_type {
             MapType::Direct => {}
             MapType::Framed => {
-                self.frames_map.remove(&vpn);
+                // the page has never been touched, or it has no PTE
+                if self.frames_map.remove(&vpn).is_none() || !self.is_accessible() {
+                    return;
+                }
             }
         }
         page_table.unmap(vpn);
@@ -111,23 +204,107 @@ impl VmArea {
         }
     }
 
-    /// Copy data to the memory area
-    /// data: start-aligned but maybe with shorter length
-    /// assume that all frames were cleared before
-    pub fn copy_data(&mut self, page_table: &mut PageTable, data: &[u8]) {
+    /// Create a copy of this area in `page_table` with its own frames and the same content
+    /// only the mapped pages are copied
+    pub fn duplicate(&self, page_table: &mut PageTable) -> Result<Self, AllocError> {
+        let mut new_area = Self::from_another(self);
+        for (&vpn, frame) in self.frames_map.iter() {
+            new_area.map_one(page_table, vpn)?;
+            let dst_ppn = new_area.frames_map[&vpn].ppn;
+            dst_ppn.get_bytes_mut().copy_from_slice(frame.ppn.get_bytes_mut());
+        }
+        Ok(new_area)
+    }
+
+    /// Share the mapped frames of this area with `child_page_table` copy-on-write
+    /// writable pages become read-only in both page tables until one of them writes
+    pub fn share_cow(&self, page_table: &mut PageTable, child_page_table: &mut PageTable) -> Result<Self, AllocError> {
+        let mut child_area = Self::from_another(self);
+        let flags = self.pte_flags() - PTEFlags::W;
+        for (&vpn, frame) in self.frames_map.iter() {
+            if self.is_accessible() {
+                if let Some(pte) = page_table.find_pte(vpn) {
+                    pte.set_flags(flags | PTEFlags::V);
+                }
+                child_page_table.map(vpn, frame.ppn, flags)?;
+            }
+            child_area.frames_map.insert(vpn, frame.clone());
+        }
+        Ok(child_area)
+    }
+
+    /// Resolve a page fault at `vpn` which requires `access`
+    /// an untouched page gets a zeroed frame, a write to a shared page gets a private copy
+    /// return Ok(false) if the access is not allowed by the area
+    pub fn handle_fault(
+        &mut self, page_table: &mut PageTable, vpn: VirtPageNum, access: PTEFlags,
+    ) -> Result<bool, AllocError> {
+        let allowed = self.pte_flags();
+        if !allowed.contains(access | PTEFlags::U) || self.map_type != MapType::Framed {
+            return Ok(false);
+        }
+        if !self.is_mapped(vpn) {
+            self.map_one(page_table, vpn)?;
+            return Ok(true);
+        }
+        if !access.contains(PTEFlags::W) {
+            return Ok(false);
+        }
+        self.copy_on_write(page_table, vpn)
+    }
+
+    /// Resolve a write to a copy-on-write page of this area
+    fn copy_on_write(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> Result<bool, AllocError> {
+        let Some(frame) = self.frames_map.get(&vpn) else {
+            return Ok(false);
+        };
+        let Some(pte) = page_table.find_pte(vpn) else {
+            return Ok(false);
+        };
+        let flags = self.pte_flags() | PTEFlags::V;
+        if Arc::strong_count(frame) > 1 {
+            // still shared, write to a private copy
+            let new_frame = Frame::alloc()?;
+            new_frame.ppn.get_bytes_mut().copy_from_slice(frame.ppn.get_bytes_mut());
+            *pte = PageTableEntry::new(new_frame.ppn, flags);
+            self.frames_map.insert(vpn, Arc::new(new_frame));
+        } else {
+            // the other sharers are gone, take the frame over
+            pte.set_flags(flags);
+        }
+        Ok(true)
+    }
+
+    /// Copy data to `start_va` in the memory area, the pages covered by data are mapped first
+    pub fn copy_data(&mut self, page_table: &mut PageTable, start_va: VirtAddr, data: &[u8]) -> Result<(), AllocError> {
         assert_eq!(self.map_type, MapType::Framed);
-        let mut start: usize = 0;
-        let mut current_vpn = self.vpns.start;
-        let len = data.len();
-        loop {
-            let src = &data[start..len.min(start + PAGE_SIZE)];
-            let dst = &mut page_table.vpn2ppn(current_vpn).unwrap().get_bytes_mut()[..src.len()];
-            dst.copy_from_slice(src);
-            start += PAGE_SIZE;
-            if start >= len {
-                break;
+        let mut copied = 0;
+        while copied < data.len() {
+            let va = VirtAddr::from(start_va.0 + copied);
+            let vpn = va.floor();
+            assert!(self.vpns.contains(&vpn), "Copying data out of the area");
+            if !self.is_mapped(vpn) {
+                self.map_one(page_table, vpn)?;
             }
-            current_vpn = VirtPageNum(current_vpn.0 + 1);
+            // the data may start in the middle of the first page
+            let offset = va.page_offset();
+            let len = (PAGE_SIZE - offset).min(data.len() - copied);
+            let dst = &mut page_table.vpn2ppn(vpn).unwrap().get_bytes_mut()[offset..offset + len];
+            dst.copy_from_slice(&data[copied..copied + len]);
+            copied += len;
         }
+        Ok(())
+    }
+}
+
+impl Debug for VmArea {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let start_va: VirtAddr = self.vpns.start.into();
+        let end_va: VirtAddr = self.vpns.end.into();
+        write!(
+            f,
+            "VmArea [{:#x}, {:#x}) {:?} {:?}",
+            start_va.0, end_va.0, self.perm, self.map_type
+        )
     }
 }
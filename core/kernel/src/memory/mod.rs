@@ -1,21 +1,48 @@
+pub use address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
+pub use frame_allocator::{AllocError, Frames};
+pub use global_allocator::{HeapStats, heap_stats};
 use log::info;
-use memory_space::{KERNEL_SPACE, remap_test};
+pub use memory_space::{ElfInfo, KERNEL_SPACE, MemorySpace, kernel_satp, vm_area::MapPermission};
+use memory_space::{brk_test, mmap_test, remap_test};
+pub use page_table::PTEFlags;
+pub use slab::{CacheStats, KmemCache, SlabBox, SlabCache, kmem_cache_shrink_all, kmem_cache_stats};
+pub use user_access::{
+    UserAccess, UserBuffer, copy_from_user, copy_to_user, get_user, put_user, translated_byte_buffer, translated_str,
+};
 
 mod address;
 mod frame_allocator;
 mod global_allocator;
 mod memory_space;
 mod page_table;
+mod slab;
+mod user_access;
 
-pub unsafe fn init() {
+/// Initialize the kernel heap, it is needed before the device tree can be parsed
+///
+/// # Safety
+/// It must be called once by the boot hart, before anything is allocated
+pub unsafe fn init_heap() {
     unsafe {
         info!("Initializing Global heap allocator...");
         global_allocator::init_heap();
-        info!("Initializing Frame allocator...");
-        frame_allocator::init_frame_allocator();
-        info!("Initializing Kernel memory space...");
-        KERNEL_SPACE.exclusive_access().activate();
-        info!("test kernel space");
-        remap_test();
     }
 }
+
+/// Initialize the frame allocator and switch to the kernel memory space
+///
+/// # Safety
+/// It must be called once by the boot hart after `init_heap` and `machine::init`, before the other
+/// harts are started: the frame allocator takes all usable memory and satp is switched under the running code
+pub unsafe fn init() {
+    info!("Initializing Frame allocator...");
+    frame_allocator::init_frame_allocator();
+    global_allocator::heap_grow_test();
+    slab::slab_test();
+    info!("Initializing Kernel memory space...");
+    KERNEL_SPACE.lock().activate();
+    info!("test kernel space");
+    remap_test();
+    mmap_test();
+    brk_test();
+}
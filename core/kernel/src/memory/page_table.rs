@@ -1,15 +1,17 @@
 use alloc::{vec, vec::Vec};
-use core::str;
+use core::{mem::size_of, str};
 
 use bitflags::*;
 
 use super::{
     address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum},
-    frame_allocator::Frame,
+    frame_allocator::AllocError,
+    slab::{KmemCache, SlabBox, kmem_cache_shrink_all},
 };
+use crate::{config::PAGE_SIZE, syscall::Errno};
 
 bitflags! {
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Copy)]
     pub struct PTEFlags: u8 {
         const V = 1 << 0; // Valid
         const R = 1 << 1; // Readable
@@ -57,39 +59,70 @@ impl PageTableEntry {
         PTEFlags::from_bits(self.bits as u8).unwrap()
     }
 
+    /// Replace the flags of the page table entry, keep the physical page number
+    pub fn set_flags(&mut self, flags: PTEFlags) {
+        self.bits = (self.bits & !0xff) | flags.bits() as usize;
+    }
+
     /// Check if the page table entry is valid
     pub fn is_valid(&self) -> bool {
         (self.flags() & PTEFlags::V) != PTEFlags::empty()
     }
 }
 
+/// A page of page table entries, the nodes of all page tables come from a slab cache
+#[repr(C, align(4096))]
+struct PageTableNode([PageTableEntry; PAGE_SIZE / size_of::<PageTableEntry>()]);
+
+lazy_static! {
+    static ref PAGE_TABLE_CACHE: KmemCache<PageTableNode> = KmemCache::new("page_table");
+}
+
+impl PageTableNode {
+    /// Allocate a node without valid entries
+    /// the frames kept by the empty slabs of all caches are released if there is no free frame
+    fn alloc() -> Result<SlabBox<Self>, AllocError> {
+        // an all zero entry is invalid
+        unsafe { PAGE_TABLE_CACHE.alloc_zeroed() }
+            .or_else(|| {
+                kmem_cache_shrink_all();
+                unsafe { PAGE_TABLE_CACHE.alloc_zeroed() }
+            })
+            .ok_or(AllocError)
+    }
+
+    fn ppn(&self) -> PhysPageNum {
+        PhysAddr::from(self as *const Self as usize).floor()
+    }
+}
+
 pub struct PageTable {
     root_ppn: PhysPageNum,
-    frames: Vec<Frame>,
+    nodes: Vec<SlabBox<PageTableNode>>,
 }
 
 impl PageTable {
     /// Create a new page table
-    pub fn new() -> Self {
-        let frame = Frame::alloc().expect("Frame alloc fail: Out of memory");
-        let root_ppn = frame.ppn.into();
-        let frames = vec![frame];
-        Self { root_ppn, frames }
+    pub fn new() -> Result<Self, AllocError> {
+        let root = PageTableNode::alloc()?;
+        let root_ppn = root.ppn();
+        let nodes = vec![root];
+        Ok(Self { root_ppn, nodes })
     }
 
     /// Get the root physical page number from satp register
     /// use this function when switching page table
-    /// this page table doesn't have any frames
+    /// this page table doesn't own any nodes
     pub fn from_satp(satp: usize) -> Self {
         Self {
             root_ppn: PhysPageNum::from(satp & ((1usize << 44) - 1)),
-            frames: Vec::new(),
+            nodes: Vec::new(),
         }
     }
 
     /// Find the page table entry for the given virtual page number,
-    /// or create a new one if it doesn't exist
-    pub fn find_pte_or_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+    /// or create a new one if it doesn't exist, which fails if the page tables can't be allocated
+    pub fn find_pte_or_create(&mut self, vpn: VirtPageNum) -> Result<&mut PageTableEntry, AllocError> {
         let idxs = vpn.get_idxs();
         let mut ppn = self.root_ppn;
         let mut result = None;
@@ -104,13 +137,13 @@ impl PageTable {
 
             // if the next level page table(except the leaf node) doesn't exist, create it
             if !pte.is_valid() {
-                let frame = Frame::alloc().expect("Frame alloc fail: Out of memory");
-                *pte = PageTableEntry::new(frame.ppn, PTEFlags::V);
-                self.frames.push(frame);
+                let node = PageTableNode::alloc()?;
+                *pte = PageTableEntry::new(node.ppn(), PTEFlags::V);
+                self.nodes.push(node);
             }
             ppn = pte.ppn();
         }
-        result
+        Ok(result.unwrap())
     }
 
     /// Find the page table entry for the given virtual page number
@@ -134,10 +167,11 @@ impl PageTable {
     }
 
     /// Map the given virtual page to the given physical page
-    pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
-        let pte = self.find_pte_or_create(vpn).unwrap();
+    pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) -> Result<(), AllocError> {
+        let pte = self.find_pte_or_create(vpn)?;
         debug_assert!(!pte.is_valid(), "Mapping an already mapped virt page: {:#x?}", vpn);
         *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+        Ok(())
     }
 
     /// Unmap the given virtual page
@@ -165,6 +199,17 @@ impl PageTable {
         })
     }
 
+    /// Translate a user page which the kernel is going to access on behalf of U-mode
+    /// the page must be valid, accessible from U-mode and allow the `access`(R and/or W)
+    pub fn translate_user(&self, vpn: VirtPageNum, access: PTEFlags) -> Result<PhysPageNum, Errno> {
+        let pte = self.find_pte(vpn).ok_or(Errno::EFAULT)?;
+        if pte.flags().contains(access | PTEFlags::U) {
+            Ok(pte.ppn())
+        } else {
+            Err(Errno::EFAULT)
+        }
+    }
+
     /// Construct the satp token from the root physical page number,
     /// default enable SV39 mode
     pub fn satp_token(&self) -> usize {
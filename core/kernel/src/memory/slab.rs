@@ -0,0 +1,370 @@
+//! Slab allocator module
+//! A `KmemCache<T>` hands out fixed-size objects of type T from slabs of contiguous frames.
+//! Each cache keeps its slabs in partial/full/free sets, empty slabs are kept for reuse
+//! until the cache is shrunk, and a freed object finds its slab by its address. Caches register
+//! themselves on first use, so the statistics of all caches can be listed and all of them can be
+//! shrunk under memory pressure.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use core::{
+    fmt::{self, Debug},
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+use log::info;
+
+use super::{address::PhysAddr, frame_allocator::Frames};
+use crate::{config::PAGE_SIZE, sync::SpinLock};
+
+/// A slab holds at least this many objects smaller than a page
+/// objects of a page or more get a slab each, so their slabs don't need contiguous frames beyond the object
+const MIN_OBJS_PER_SLAB: usize = 8;
+
+lazy_static! {
+    /// All caches which have allocated a slab
+    static ref CACHES: SpinLock<Vec<&'static dyn SlabCache>> = SpinLock::new(Vec::new());
+}
+
+/// Statistics of a cache
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub name: &'static str,
+    /// Size of an object slot, including the padding
+    pub obj_size: usize,
+    pub objs_per_slab: usize,
+    pub partial_slabs: usize,
+    pub full_slabs: usize,
+    pub free_slabs: usize,
+    /// Objects currently allocated
+    pub objs_in_use: usize,
+    pub total_allocs: usize,
+    pub total_frees: usize,
+}
+
+/// Type erased interface of a cache, used by the cache registry
+pub trait SlabCache: Sync {
+    fn stats(&self) -> CacheStats;
+
+    /// Release the empty slabs, return the number of pages released
+    fn shrink(&self) -> usize;
+}
+
+/// A slab of `pages` contiguous frames cut into object slots
+/// free slots are chained through their first word
+struct Slab {
+    frames: Frames,
+    /// Address of the first free slot, 0 if the slab is full
+    free_head: usize,
+    in_use: usize,
+}
+
+impl Slab {
+    fn new(pages: usize, obj_size: usize, objs: usize) -> Option<Self> {
+        let frames = Frames::try_alloc(pages)?;
+        // physical memory is mapped directly in the kernel space
+        let start = PhysAddr::from(frames.ppn).0;
+        for i in 0..objs {
+            let slot = start + i * obj_size;
+            let next = if i + 1 < objs { slot + obj_size } else { 0 };
+            unsafe { (slot as *mut usize).write(next) };
+        }
+        Some(Self {
+            frames,
+            free_head: start,
+            in_use: 0,
+        })
+    }
+
+    fn start(&self) -> usize {
+        PhysAddr::from(self.frames.ppn).0
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        (self.start()..self.start() + self.frames.num * PAGE_SIZE).contains(&addr)
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.free_head == 0 {
+            return None;
+        }
+        let slot = self.free_head;
+        self.free_head = unsafe { (slot as *const usize).read() };
+        self.in_use += 1;
+        Some(slot)
+    }
+
+    fn push(&mut self, slot: usize) {
+        unsafe { (slot as *mut usize).write(self.free_head) };
+        self.free_head = slot;
+        self.in_use -= 1;
+    }
+}
+
+struct CacheInner {
+    /// All slabs by their start address
+    slabs: BTreeMap<usize, Slab>,
+    /// Start addresses of the slabs with free and used slots, with only used slots and with only free slots
+    partial: BTreeSet<usize>,
+    full: BTreeSet<usize>,
+    free: BTreeSet<usize>,
+    total_allocs: usize,
+    total_frees: usize,
+    registered: bool,
+}
+
+/// A named cache of objects of type T
+pub struct KmemCache<T> {
+    name: &'static str,
+    obj_size: usize,
+    objs_per_slab: usize,
+    slab_pages: usize,
+    inner: SpinLock<CacheInner>,
+    /// the cache only hands out slots, it doesn't own any T itself
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> KmemCache<T> {
+    /// Create an empty cache, slabs are allocated on demand
+    /// the lock of each cache is a lock class of its own, named after the caller
+    #[track_caller]
+    pub fn new(name: &'static str) -> Self {
+        assert!(
+            align_of::<T>() <= PAGE_SIZE,
+            "Slab objects can't be aligned to more than a page"
+        );
+        // a free slot must be able to hold the link to the next one
+        let align = align_of::<T>().max(align_of::<usize>());
+        let obj_size = size_of::<T>().max(size_of::<usize>()).next_multiple_of(align);
+        let objs = if obj_size < PAGE_SIZE { MIN_OBJS_PER_SLAB } else { 1 };
+        let slab_pages = (obj_size * objs).div_ceil(PAGE_SIZE).next_power_of_two();
+        Self {
+            name,
+            obj_size,
+            objs_per_slab: slab_pages * PAGE_SIZE / obj_size,
+            slab_pages,
+            inner: SpinLock::new(CacheInner {
+                slabs: BTreeMap::new(),
+                partial: BTreeSet::new(),
+                full: BTreeSet::new(),
+                free: BTreeSet::new(),
+                total_allocs: 0,
+                total_frees: 0,
+                registered: false,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Move `value` into an object of the cache, return None if there is no memory for a new slab
+    pub fn alloc(&'static self, value: T) -> Option<SlabBox<T>> {
+        let ptr = self.alloc_slot()?;
+        unsafe { ptr.write(value) };
+        Some(SlabBox {
+            ptr: NonNull::new(ptr).unwrap(),
+            cache: self,
+        })
+    }
+
+    /// Allocate an object filled with zeros, without building it on the stack first
+    ///
+    /// # Safety
+    /// All zero bytes must be a valid `T`
+    pub unsafe fn alloc_zeroed(&'static self) -> Option<SlabBox<T>> {
+        let ptr = self.alloc_slot()?;
+        unsafe { ptr::write_bytes(ptr, 0, 1) };
+        Some(SlabBox {
+            ptr: NonNull::new(ptr).unwrap(),
+            cache: self,
+        })
+    }
+
+    /// Take a free slot, a new slab is allocated if there is none
+    fn alloc_slot(&'static self) -> Option<*mut T> {
+        let mut guard = self.inner.lock();
+        let inner = &mut *guard;
+        let start = match inner.partial.last() {
+            Some(&start) => start,
+            None => {
+                let start = match inner.free.pop_last() {
+                    Some(start) => start,
+                    None => {
+                        let slab = Slab::new(self.slab_pages, self.obj_size, self.objs_per_slab)?;
+                        let start = slab.start();
+                        inner.slabs.insert(start, slab);
+                        start
+                    }
+                };
+                inner.partial.insert(start);
+                start
+            }
+        };
+        let slab = inner.slabs.get_mut(&start).unwrap();
+        let slot = slab.pop().unwrap();
+        if slab.in_use == self.objs_per_slab {
+            inner.partial.remove(&start);
+            inner.full.insert(start);
+        }
+        inner.total_allocs += 1;
+        let register = !inner.registered;
+        inner.registered = true;
+        drop(guard);
+        if register {
+            CACHES.lock().push(self);
+        }
+        Some(slot as *mut T)
+    }
+
+    /// Return the slot at `slot` to its slab, the object has been dropped
+    fn free(&self, slot: usize) {
+        let mut guard = self.inner.lock();
+        let inner = &mut *guard;
+        inner.total_frees += 1;
+        // the slab starting at or right before the slot
+        let (&start, slab) = inner
+            .slabs
+            .range_mut(..=slot)
+            .next_back()
+            .filter(|(_, slab)| slab.contains(slot))
+            .expect("Freeing an object which is not from this cache");
+        let was_full = slab.in_use == self.objs_per_slab;
+        slab.push(slot);
+        let (from, to) = match (was_full, slab.in_use == 0) {
+            (true, true) => (&mut inner.full, &mut inner.free),
+            (true, false) => (&mut inner.full, &mut inner.partial),
+            (false, true) => (&mut inner.partial, &mut inner.free),
+            (false, false) => return,
+        };
+        from.remove(&start);
+        to.insert(start);
+    }
+}
+
+impl<T> SlabCache for KmemCache<T> {
+    fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock();
+        let objs_in_use = inner.slabs.values().map(|slab| slab.in_use).sum();
+        CacheStats {
+            name: self.name,
+            obj_size: self.obj_size,
+            objs_per_slab: self.objs_per_slab,
+            partial_slabs: inner.partial.len(),
+            full_slabs: inner.full.len(),
+            free_slabs: inner.free.len(),
+            objs_in_use,
+            total_allocs: inner.total_allocs,
+            total_frees: inner.total_frees,
+        }
+    }
+
+    fn shrink(&self) -> usize {
+        let free: Vec<Slab> = {
+            let mut guard = self.inner.lock();
+            let inner = &mut *guard;
+            let starts = core::mem::take(&mut inner.free);
+            starts.iter().map(|start| inner.slabs.remove(start).unwrap()).collect()
+        };
+        // the frames are released with the cache unlocked
+        free.len() * self.slab_pages
+    }
+}
+
+/// An owned object in a KmemCache, it goes back to the cache when dropped
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static KmemCache<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.ptr.as_ptr()) };
+        self.cache.free(self.ptr.as_ptr() as usize);
+    }
+}
+
+impl<T: Debug> Debug for SlabBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+/// Get the statistics of all caches
+pub fn kmem_cache_stats() -> Vec<CacheStats> {
+    CACHES.lock().iter().map(|cache| cache.stats()).collect()
+}
+
+/// Shrink all caches, called under memory pressure, return the number of pages released
+pub fn kmem_cache_shrink_all() -> usize {
+    // shrink outside the registry, a cache may be registered at the same time
+    let caches: Vec<&'static dyn SlabCache> = CACHES.lock().clone();
+    caches.iter().map(|cache| cache.shrink()).sum()
+}
+
+#[allow(unused)]
+pub fn slab_test() {
+    info!("Testing slab allocator...");
+    struct TestObject {
+        id: usize,
+        payload: [u8; 120],
+    }
+    lazy_static! {
+        static ref TEST_CACHE: KmemCache<TestObject> = KmemCache::new("slab_test");
+    }
+    let per_slab = TEST_CACHE.objs_per_slab;
+    let mut objects: Vec<SlabBox<TestObject>> = (0..per_slab * 2 + 1)
+        .map(|id| {
+            TEST_CACHE
+                .alloc(TestObject {
+                    id,
+                    payload: [id as u8; 120],
+                })
+                .expect("Slab alloc fail: Out of memory")
+        })
+        .collect();
+    for (id, object) in objects.iter().enumerate() {
+        assert_eq!(object.id, id);
+        assert!(object.payload.iter().all(|&b| b == id as u8));
+    }
+    let stats = TEST_CACHE.stats();
+    assert_eq!((stats.full_slabs, stats.partial_slabs), (2, 1));
+    assert_eq!(stats.objs_in_use, per_slab * 2 + 1);
+    // a freed slot is reused by the next allocation
+    let freed = &*objects.swap_remove(0) as *const TestObject as usize;
+    let reused = TEST_CACHE
+        .alloc(TestObject {
+            id: 0,
+            payload: [0; 120],
+        })
+        .unwrap();
+    assert_eq!(&*reused as *const TestObject as usize, freed);
+    drop(reused);
+    drop(objects);
+    let stats = TEST_CACHE.stats();
+    assert_eq!((stats.objs_in_use, stats.free_slabs), (0, 3));
+    assert!(kmem_cache_stats().iter().any(|stats| stats.name == "slab_test"));
+    assert_eq!(kmem_cache_shrink_all(), 3 * TEST_CACHE.slab_pages);
+    assert_eq!(TEST_CACHE.stats().free_slabs, 0);
+    info!("Slab allocator test passed!");
+}
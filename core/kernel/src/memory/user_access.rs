@@ -0,0 +1,169 @@
+//! User memory access module
+//! Access user buffers through a foreign memory space, every page is checked before it is touched,
+//! so a bad pointer from U-mode ends up with EFAULT instead of a kernel panic
+//! Pages which need a fault to be resolved first(e.g. copy-on-write) are resolved by the memory space
+
+use alloc::{string::String, vec::Vec};
+use core::{
+    iter::Flatten,
+    mem::{MaybeUninit, size_of},
+    slice,
+};
+
+use super::{
+    address::{VirtAddr, VirtPageNum},
+    memory_space::MemorySpace,
+    page_table::PTEFlags,
+};
+use crate::{
+    config::{PAGE_SIZE, USER_SPACE_END},
+    syscall::Errno,
+};
+
+/// Max length of a string read from user space, including the terminating NUL
+const USER_STR_MAX: usize = 4096;
+
+/// Kind of access the kernel makes to a user buffer
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UserAccess {
+    /// The kernel reads the buffer, e.g. the buffer of write()
+    Read,
+    /// The kernel writes the buffer, e.g. the buffer of read()
+    Write,
+}
+
+impl UserAccess {
+    fn pte_flags(self) -> PTEFlags {
+        match self {
+            UserAccess::Read => PTEFlags::R,
+            UserAccess::Write => PTEFlags::W,
+        }
+    }
+}
+
+/// Translate a user buffer into byte slices which the kernel can access, one slice per page
+/// every page must be mapped with the U bit and the permission required by `access`
+pub fn translated_byte_buffer(
+    space: &mut MemorySpace, ptr: *const u8, len: usize, access: UserAccess,
+) -> Result<Vec<&'static mut [u8]>, Errno> {
+    let mut start = ptr as usize;
+    let end = start.checked_add(len).ok_or(Errno::EFAULT)?;
+    if end > USER_SPACE_END {
+        return Err(Errno::EFAULT);
+    }
+    let mut buffers = Vec::new();
+    while start < end {
+        let start_va = VirtAddr::from(start);
+        let vpn = start_va.floor();
+        let ppn = space.translate_user(vpn, access.pte_flags())?;
+        let next_page_va: VirtAddr = VirtPageNum(vpn.0 + 1).into();
+        let end_va = next_page_va.min(VirtAddr::from(end));
+        let bytes = ppn.get_bytes_mut();
+        if end_va.page_offset() == 0 {
+            buffers.push(&mut bytes[start_va.page_offset()..]);
+        } else {
+            buffers.push(&mut bytes[start_va.page_offset()..end_va.page_offset()]);
+        }
+        start = end_va.into();
+    }
+    Ok(buffers)
+}
+
+/// Copy `dst.len()` bytes from the user address `src` into `dst`
+pub fn copy_from_user(space: &mut MemorySpace, src: *const u8, dst: &mut [u8]) -> Result<(), Errno> {
+    let buffers = translated_byte_buffer(space, src, dst.len(), UserAccess::Read)?;
+    let mut copied = 0;
+    for buffer in buffers {
+        dst[copied..copied + buffer.len()].copy_from_slice(buffer);
+        copied += buffer.len();
+    }
+    Ok(())
+}
+
+/// Copy `src` to the user address `dst`
+pub fn copy_to_user(space: &mut MemorySpace, dst: *mut u8, src: &[u8]) -> Result<(), Errno> {
+    let buffers = translated_byte_buffer(space, dst, src.len(), UserAccess::Write)?;
+    let mut copied = 0;
+    for buffer in buffers {
+        buffer.copy_from_slice(&src[copied..copied + buffer.len()]);
+        copied += buffer.len();
+    }
+    Ok(())
+}
+
+/// Read a value of type T from user space, T must be valid for any bit pattern
+pub fn get_user<T: Copy>(space: &mut MemorySpace, src: *const T) -> Result<T, Errno> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let dst = unsafe { slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    copy_from_user(space, src as *const u8, dst)?;
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Write a value of type T to user space
+pub fn put_user<T: Copy>(space: &mut MemorySpace, dst: *mut T, value: &T) -> Result<(), Errno> {
+    let src = unsafe { slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    copy_to_user(space, dst as *mut u8, src)
+}
+
+/// Read a NUL-terminated string from user space, the string may cross pages
+pub fn translated_str(space: &mut MemorySpace, ptr: *const u8) -> Result<String, Errno> {
+    let mut bytes = Vec::new();
+    let mut va = ptr as usize;
+    loop {
+        if va >= USER_SPACE_END {
+            return Err(Errno::EFAULT);
+        }
+        let ppn = space.translate_user(VirtAddr::from(va).floor(), PTEFlags::R)?;
+        let page = ppn.get_bytes_mut();
+        let offset = va % PAGE_SIZE;
+        match page[offset..].iter().position(|&b| b == 0) {
+            Some(len) => {
+                bytes.extend_from_slice(&page[offset..offset + len]);
+                break;
+            }
+            None => {
+                bytes.extend_from_slice(&page[offset..]);
+                va += PAGE_SIZE - offset;
+            }
+        }
+        if bytes.len() >= USER_STR_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| Errno::EINVAL)
+}
+
+/// A user buffer which has been translated and checked, it may consist of several pages
+pub struct UserBuffer {
+    pub buffers: Vec<&'static mut [u8]>,
+}
+
+/// Iterator over the bytes of a UserBuffer
+pub type UserBufferIter = Flatten<alloc::vec::IntoIter<&'static mut [u8]>>;
+
+impl UserBuffer {
+    /// Translate and check the user buffer [ptr, ptr + len) for `access`
+    pub fn new(space: &mut MemorySpace, ptr: *const u8, len: usize, access: UserAccess) -> Result<Self, Errno> {
+        Ok(Self {
+            buffers: translated_byte_buffer(space, ptr, len, access)?,
+        })
+    }
+
+    /// Total length of the buffer in bytes
+    pub fn len(&self) -> usize {
+        self.buffers.iter().map(|buffer| buffer.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl IntoIterator for UserBuffer {
+    type IntoIter = UserBufferIter;
+    type Item = &'static mut u8;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.buffers.into_iter().flatten()
+    }
+}
@@ -1,13 +1,101 @@
-use sbi_rt::{NoReason, Shutdown, SystemFailure, system_reset};
+use core::sync::atomic::{AtomicU8, Ordering};
 
-pub fn console_write_char(c: usize) {
+use sbi_rt::{Console, NoReason, Physical, SbiRet, Shutdown, SystemFailure, system_reset};
+
+use crate::sync::SpinLock;
+
+/// Size of the buffer passed to the DBCN extension
+const DBCN_BUFFER_SIZE: usize = 256;
+
+const DBCN_UNKNOWN: u8 = 0;
+const DBCN_AVAILABLE: u8 = 1;
+const DBCN_UNAVAILABLE: u8 = 2;
+
+/// Whether the firmware implements the Debug Console extension, probed on first use
+static DBCN: AtomicU8 = AtomicU8::new(DBCN_UNKNOWN);
+
+/// DBCN takes physical addresses, and kernel stacks are not mapped directly,
+/// so bytes are passed through this buffer in .bss where virtual and physical addresses are equal
+static DBCN_BUFFER: SpinLock<[u8; DBCN_BUFFER_SIZE]> = SpinLock::new([0; DBCN_BUFFER_SIZE]);
+
+fn has_dbcn() -> bool {
+    match DBCN.load(Ordering::Relaxed) {
+        DBCN_UNKNOWN => {
+            let available = sbi_rt::probe_extension(Console).is_available();
+            let state = if available { DBCN_AVAILABLE } else { DBCN_UNAVAILABLE };
+            DBCN.store(state, Ordering::Relaxed);
+            available
+        }
+        state => state == DBCN_AVAILABLE,
+    }
+}
+
+/// Write bytes to the SBI console, through DBCN if the firmware has it, one legacy call per byte otherwise
+pub fn console_write(bytes: &[u8]) {
+    // the buffer is held if a print on this hart panics, the panic message goes out the legacy way
+    if has_dbcn() && !DBCN_BUFFER.is_held_by_current_hart() {
+        let mut buffer = DBCN_BUFFER.lock();
+        for chunk in bytes.chunks(DBCN_BUFFER_SIZE) {
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            let mut written = 0;
+            while written < chunk.len() {
+                let remaining = &buffer[written..chunk.len()];
+                let ret = sbi_rt::console_write(Physical::new(remaining.len(), remaining.as_ptr() as usize, 0));
+                if ret.is_err() {
+                    legacy_write(remaining);
+                    break;
+                }
+                written += ret.value;
+            }
+        }
+    } else {
+        legacy_write(bytes);
+    }
+}
+
+/// Write bytes one by one with the legacy console extension, a multi-byte char is sent byte by byte
+pub fn legacy_write(bytes: &[u8]) {
+    for &byte in bytes {
+        #[allow(deprecated)]
+        sbi_rt::legacy::console_putchar(byte as usize);
+    }
+}
+
+/// Read the bytes the SBI console has received into `buf` without blocking, return the number of bytes read
+pub fn console_read(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    if has_dbcn() {
+        let mut buffer = DBCN_BUFFER.lock();
+        let len = buf.len().min(DBCN_BUFFER_SIZE);
+        let ret = sbi_rt::console_read(Physical::new(len, buffer.as_mut_ptr() as usize, 0));
+        if ret.is_ok() {
+            buf[..ret.value].copy_from_slice(&buffer[..ret.value]);
+            return ret.value;
+        }
+    }
+    // the legacy call returns -1 when there is nothing to read
     #[allow(deprecated)]
-    sbi_rt::legacy::console_putchar(c);
+    match sbi_rt::legacy::console_getchar() as isize {
+        -1 => 0,
+        byte => {
+            buf[0] = byte as u8;
+            1
+        }
+    }
 }
 
-// pub fn console_read_char() -> usize {
-//     sbi_rt::console_read(bytes)
-// }
+/// Set the timer to trigger an interrupt when `time` reaches `stime_value`
+pub fn set_timer(stime_value: usize) {
+    sbi_rt::set_timer(stime_value as u64);
+}
+
+/// Start `hartid` at `start_addr` in S-mode with a0 = hartid and a1 = opaque
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> Result<(), SbiRet> {
+    let ret = sbi_rt::hart_start(hartid, start_addr, opaque);
+    if ret.is_ok() { Ok(()) } else { Err(ret) }
+}
 
 pub fn shutdown(failure: bool) -> ! {
     if !failure {
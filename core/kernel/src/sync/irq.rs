@@ -0,0 +1,40 @@
+//! Interrupt state module
+//! Disabling interrupts nests per hart, they are enabled again only when the outermost
+//! `push_off` is paired with its `pop_off` and they were enabled before it
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use riscv::register::sstatus;
+
+use crate::{config::MAX_HARTS, cpu::hart_id};
+
+/// Depth of `push_off` on each hart
+static NESTING: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+/// Whether interrupts were enabled before the outermost `push_off` on each hart
+static WAS_ENABLED: [AtomicBool; MAX_HARTS] = [const { AtomicBool::new(false) }; MAX_HARTS];
+
+/// Disable interrupts on the hart
+pub fn push_off() {
+    let enabled = sstatus::read().sie();
+    unsafe {
+        sstatus::clear_sie();
+    }
+    // interrupts are off, so the hart can't change below
+    let hart = hart_id();
+    if NESTING[hart].fetch_add(1, Ordering::Relaxed) == 0 {
+        WAS_ENABLED[hart].store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// Undo a `push_off`, enable interrupts if it is the outermost one and they were enabled
+pub fn pop_off() {
+    let hart = hart_id();
+    assert!(!sstatus::read().sie(), "pop_off with interrupts enabled");
+    let depth = NESTING[hart].fetch_sub(1, Ordering::Relaxed);
+    assert!(depth > 0, "pop_off without push_off");
+    if depth == 1 && WAS_ENABLED[hart].load(Ordering::Relaxed) {
+        unsafe {
+            sstatus::set_sie();
+        }
+    }
+}
@@ -0,0 +1,379 @@
+//! Lock dependency validator module
+//! With the `lockdep` feature in debug builds, every spin lock belongs to a class(the place it is created),
+//! and the order in which classes are acquired on each hart is recorded as a graph.
+//! Acquiring a class while holding another adds an edge, an edge which closes a cycle is a possible
+//! deadlock and is reported with the call sites of both orders, even if it never actually deadlocks.
+//! All instances created at the same place share a class, e.g. the inner locks of all tasks, and
+//! nesting two locks of the same class isn't checked, so inversions between such instances are not seen.
+//! Constructors which create a lock for each caller, like `KmemCache::new`, pass the caller on.
+//! Without the feature a `LockClass` is empty and all checks compile to nothing.
+
+use core::panic::Location;
+
+/// The lock class of a lock, identified by the place the lock is created
+pub struct LockClass {
+    #[cfg(all(feature = "lockdep", debug_assertions))]
+    inner: imp::ClassId,
+}
+
+impl LockClass {
+    #[track_caller]
+    pub const fn new() -> Self {
+        Self {
+            #[cfg(all(feature = "lockdep", debug_assertions))]
+            inner: imp::ClassId::new(Location::caller()),
+        }
+    }
+
+    /// The lock is about to be acquired at `site`, check the order against the held locks
+    #[inline]
+    pub fn acquire(&self, site: &'static Location<'static>) {
+        #[cfg(all(feature = "lockdep", debug_assertions))]
+        imp::acquire(&self.inner, site);
+        #[cfg(not(all(feature = "lockdep", debug_assertions)))]
+        let _ = site;
+    }
+
+    /// The lock has been acquired at `site` without waiting, e.g. by `try_lock`
+    /// it can't deadlock so no order is checked, but locks acquired while holding it are
+    #[inline]
+    pub fn try_acquired(&self, site: &'static Location<'static>) {
+        #[cfg(all(feature = "lockdep", debug_assertions))]
+        imp::try_acquired(&self.inner, site);
+        #[cfg(not(all(feature = "lockdep", debug_assertions)))]
+        let _ = site;
+    }
+
+    /// The lock has been released
+    #[inline]
+    pub fn release(&self) {
+        #[cfg(all(feature = "lockdep", debug_assertions))]
+        imp::release(&self.inner);
+    }
+}
+
+/// Number of possible deadlocks reported so far
+pub fn lockdep_reports() -> usize {
+    #[cfg(all(feature = "lockdep", debug_assertions))]
+    return imp::reports();
+    #[cfg(not(all(feature = "lockdep", debug_assertions)))]
+    0
+}
+
+#[cfg(all(feature = "lockdep", debug_assertions))]
+mod imp {
+    use core::{
+        cell::UnsafeCell,
+        hint::spin_loop,
+        panic::Location,
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    };
+
+    use log::error;
+
+    use crate::{
+        config::MAX_HARTS,
+        cpu::hart_id,
+        sync::{pop_off, push_off},
+    };
+
+    /// At most this many classes are tracked, lockdep turns itself off when there are more
+    const MAX_CLASSES: usize = 64;
+    /// At most this many locks are held at the same time on a hart
+    const MAX_HELD: usize = 16;
+    /// Class id of a lock which has never been acquired
+    const UNASSIGNED: usize = usize::MAX;
+
+    type Site = &'static Location<'static>;
+
+    pub struct ClassId {
+        /// Where the lock is created
+        site: Site,
+        /// Index in the class table, assigned on the first acquisition
+        id: AtomicUsize,
+    }
+
+    impl ClassId {
+        pub const fn new(site: Site) -> Self {
+            Self {
+                site,
+                id: AtomicUsize::new(UNASSIGNED),
+            }
+        }
+    }
+
+    /// The dependency graph and the locks held on each hart
+    /// it lives in .bss and never allocates, because the heap may take locks itself
+    struct State {
+        /// Creation site of each class
+        classes: [Option<Site>; MAX_CLASSES],
+        count: usize,
+        /// Bit j of `after[i]` is set if class j has been acquired while holding class i
+        after: [u64; MAX_CLASSES],
+        /// Sites of the first acquisition of the held class and of the new class for each edge
+        edge_sites: [[Option<(Site, Site)>; MAX_CLASSES]; MAX_CLASSES],
+        /// Classes held on each hart and where they were acquired
+        held: [[(usize, Option<Site>); MAX_HELD]; MAX_HARTS],
+        depth: [usize; MAX_HARTS],
+        /// Set when a table overflows, the graph is incomplete then
+        disabled: bool,
+    }
+
+    /// The state is protected by a raw spin lock, which is not tracked itself
+    struct StateLock {
+        locked: AtomicBool,
+        state: UnsafeCell<State>,
+    }
+
+    unsafe impl Sync for StateLock {}
+
+    static STATE: StateLock = StateLock {
+        locked: AtomicBool::new(false),
+        state: UnsafeCell::new(State {
+            classes: [None; MAX_CLASSES],
+            count: 0,
+            after: [0; MAX_CLASSES],
+            edge_sites: [[None; MAX_CLASSES]; MAX_CLASSES],
+            held: [[(UNASSIGNED, None); MAX_HELD]; MAX_HARTS],
+            depth: [0; MAX_HARTS],
+            disabled: false,
+        }),
+    };
+
+    static REPORTS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Where a lock is created and where it is acquired
+    type Use = (Site, Site);
+
+    /// A lock order inversion, copied out of the state to be logged once the state is unlocked,
+    /// because logging takes locks which are checked too
+    /// it is filled in place, it is too large for a kernel stack
+    struct Inversion {
+        held: Option<Use>,
+        new: Option<Use>,
+        /// The opposite order, the held and the acquired lock of each edge
+        path: [Option<(Use, Use)>; MAX_CLASSES],
+    }
+
+    /// The inversion being logged on each hart, only accessed by its own hart
+    struct Pending([UnsafeCell<Inversion>; MAX_HARTS]);
+
+    unsafe impl Sync for Pending {}
+
+    static PENDING: Pending = Pending(
+        [const {
+            UnsafeCell::new(Inversion {
+                held: None,
+                new: None,
+                path: [None; MAX_CLASSES],
+            })
+        }; MAX_HARTS],
+    );
+
+    /// Set while the inversion of a hart is logged, another one found meanwhile is only counted
+    static REPORTING: [AtomicBool; MAX_HARTS] = [const { AtomicBool::new(false) }; MAX_HARTS];
+
+    /// Something to log once the state is unlocked
+    enum Report {
+        /// The inversion pending on this hart
+        Inversion(usize),
+        TooManyClasses,
+        TooManyHeld(usize),
+    }
+
+    /// Run `f` with the state locked and interrupts disabled
+    fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+        push_off();
+        while STATE
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        let result = f(unsafe { &mut *STATE.state.get() });
+        STATE.locked.store(false, Ordering::Release);
+        pop_off();
+        result
+    }
+
+    impl State {
+        /// Get the index of a class, register it on the first use
+        fn class_of(&mut self, class: &ClassId) -> Option<usize> {
+            let id = class.id.load(Ordering::Relaxed);
+            if id != UNASSIGNED {
+                return Some(id);
+            }
+            // locks created at the same place share a class
+            let id = match self.classes[..self.count]
+                .iter()
+                .position(|&site| site == Some(class.site))
+            {
+                Some(id) => id,
+                None if self.count < MAX_CLASSES => {
+                    self.classes[self.count] = Some(class.site);
+                    self.count += 1;
+                    self.count - 1
+                }
+                None => {
+                    self.disabled = true;
+                    return None;
+                }
+            };
+            class.id.store(id, Ordering::Relaxed);
+            Some(id)
+        }
+
+        /// Find a path from class `from` to class `to`, return it as a list of classes
+        fn find_path(&self, from: usize, to: usize) -> Option<([usize; MAX_CLASSES], usize)> {
+            let mut parent = [UNASSIGNED; MAX_CLASSES];
+            let mut stack = [0; MAX_CLASSES];
+            let mut top = 1;
+            let mut visited = 1u64 << from;
+            stack[0] = from;
+            while top > 0 {
+                top -= 1;
+                let class = stack[top];
+                if class == to {
+                    let mut path = [0; MAX_CLASSES];
+                    let (mut len, mut node) = (0, to);
+                    while node != UNASSIGNED {
+                        path[len] = node;
+                        len += 1;
+                        node = parent[node];
+                    }
+                    path[..len].reverse();
+                    return Some((path, len));
+                }
+                let mut next = self.after[class] & !visited;
+                while next != 0 {
+                    let child = next.trailing_zeros() as usize;
+                    next &= next - 1;
+                    visited |= 1 << child;
+                    parent[child] = class;
+                    stack[top] = child;
+                    top += 1;
+                }
+            }
+            None
+        }
+
+        /// Record that `new` is acquired at `site` on `hart` while `held` is held since `held_site`
+        /// return true if it is an inversion which has been made pending on the hart
+        fn add_edge(&mut self, hart: usize, held: usize, held_site: Site, new: usize, site: Site) -> bool {
+            if held == new || self.after[held] & (1 << new) != 0 {
+                return false;
+            }
+            let mut pending = false;
+            if let Some((path, len)) = self.find_path(new, held) {
+                REPORTS.fetch_add(1, Ordering::Relaxed);
+                if !REPORTING[hart].swap(true, Ordering::Relaxed) {
+                    self.save_inversion(hart, (held, held_site), (new, site), &path[..len]);
+                    pending = true;
+                }
+            }
+            self.after[held] |= 1 << new;
+            self.edge_sites[held][new] = Some((held_site, site));
+            pending
+        }
+
+        /// Copy an inversion to the pending one of `hart`
+        fn save_inversion(&self, hart: usize, held: (usize, Site), new: (usize, Site), path: &[usize]) {
+            let name = |class: usize| self.classes[class].unwrap();
+            // nothing else touches the pending inversion of this hart while REPORTING is set
+            let inversion = unsafe { &mut *PENDING.0[hart].get() };
+            inversion.held = Some((name(held.0), held.1));
+            inversion.new = Some((name(new.0), new.1));
+            let mut pairs = path.windows(2);
+            for step in inversion.path.iter_mut() {
+                *step = pairs.next().map(|pair| {
+                    let (first_site, second_site) = self.edge_sites[pair[0]][pair[1]].unwrap();
+                    ((name(pair[0]), first_site), (name(pair[1]), second_site))
+                });
+            }
+        }
+    }
+
+    /// Log a report, the state must be unlocked
+    fn log_report(report: Report) {
+        match report {
+            Report::Inversion(hart) => {
+                let inversion = unsafe { &*PENDING.0[hart].get() };
+                let (new, held) = (inversion.new.unwrap(), inversion.held.unwrap());
+                error!("[lockdep] Possible deadlock: lock order inversion on hart {}", hart);
+                error!("[lockdep]   lock created at {} is acquired at {}", new.0, new.1);
+                error!(
+                    "[lockdep]   while holding lock created at {}, acquired at {}",
+                    held.0, held.1
+                );
+                error!("[lockdep]   but the opposite order has been seen:");
+                for (first, second) in inversion.path.iter().map_while(|step| *step) {
+                    error!(
+                        "[lockdep]   lock created at {} is acquired at {} while holding lock created at {}, acquired at {}",
+                        second.0, second.1, first.0, first.1
+                    );
+                }
+                REPORTING[hart].store(false, Ordering::Relaxed);
+            }
+            Report::TooManyClasses => error!("[lockdep] Too many lock classes, lockdep is turned off"),
+            Report::TooManyHeld(hart) => {
+                error!("[lockdep] Too many locks held on hart {}, lockdep is turned off", hart)
+            }
+        }
+    }
+
+    pub fn acquire(class: &ClassId, site: Site) {
+        push_held(class, site, true);
+    }
+
+    pub fn try_acquired(class: &ClassId, site: Site) {
+        push_held(class, site, false);
+    }
+
+    /// Record that the class is held on this hart, after checking the order if `check_order`
+    fn push_held(class: &ClassId, site: Site, check_order: bool) {
+        let report = with_state(|state| {
+            if state.disabled {
+                return None;
+            }
+            let Some(id) = state.class_of(class) else {
+                return Some(Report::TooManyClasses);
+            };
+            let hart = hart_id();
+            if state.depth[hart] == MAX_HELD {
+                state.disabled = true;
+                return Some(Report::TooManyHeld(hart));
+            }
+            let mut report = None;
+            for i in 0..state.depth[hart] {
+                let (held, held_site) = state.held[hart][i];
+                if check_order && state.add_edge(hart, held, held_site.unwrap(), id, site) {
+                    report = Some(Report::Inversion(hart));
+                }
+            }
+            state.held[hart][state.depth[hart]] = (id, Some(site));
+            state.depth[hart] += 1;
+            report
+        });
+        if let Some(report) = report {
+            log_report(report);
+        }
+    }
+
+    pub fn release(class: &ClassId) {
+        with_state(|state| {
+            let id = class.id.load(Ordering::Relaxed);
+            let hart = hart_id();
+            let depth = state.depth[hart];
+            // locks are usually released in reverse order, but not always
+            if let Some(i) = state.held[hart][..depth].iter().rposition(|&(held, _)| held == id) {
+                state.held[hart].copy_within(i + 1..depth, i);
+                state.depth[hart] -= 1;
+            }
+        });
+    }
+
+    pub fn reports() -> usize {
+        REPORTS.load(Ordering::Relaxed)
+    }
+}
@@ -1 +1,87 @@
-pub mod safe_cell;
+//! Synchronization primitives module
+
+mod irq;
+mod lockdep;
+mod mutex;
+mod semaphore;
+mod spin;
+mod ticket;
+mod wait_queue;
+
+pub use irq::{pop_off, push_off};
+pub use lockdep::lockdep_reports;
+pub use mutex::{Condvar, Mutex};
+pub use semaphore::Semaphore;
+pub use spin::{IrqSpinLock, SpinLock, SpinLockGuard};
+pub use ticket::TicketLock;
+pub use wait_queue::WaitQueue;
+
+/// Check the lock state and the interrupt state of the running hart
+#[allow(unused)]
+pub fn lock_test() {
+    log::info!("Testing locks...");
+    use riscv::register::sstatus;
+    let lock = SpinLock::new(0);
+    let mut guard = lock.lock();
+    *guard += 1;
+    assert!(lock.is_held_by_current_hart());
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    assert_eq!(*lock.try_lock().unwrap(), 1);
+
+    let irq_lock = IrqSpinLock::new(0);
+    let enabled = sstatus::read().sie();
+    let outer = irq_lock.lock();
+    assert!(!sstatus::read().sie());
+    push_off();
+    pop_off();
+    assert!(!sstatus::read().sie());
+    drop(outer);
+    assert_eq!(sstatus::read().sie(), enabled);
+
+    let ticket = TicketLock::new(0);
+    for _ in 0..3 {
+        *ticket.lock() += 1;
+    }
+    assert_eq!(*ticket.lock(), 3);
+
+    // the sleeping primitives must not block when they don't have to
+    let mutex = Mutex::new(0);
+    *mutex.lock() += 1;
+    let guard = mutex.lock();
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    assert_eq!(*mutex.try_lock().unwrap(), 1);
+    let semaphore = Semaphore::new(1);
+    semaphore.acquire();
+    assert!(!semaphore.try_acquire());
+    semaphore.release();
+    assert_eq!(semaphore.permits(), 1);
+    let condvar = Condvar::new();
+    assert!(!condvar.notify_one());
+    let guard = condvar.wait_while(mutex.lock(), |value| *value == 0);
+    assert_eq!(*guard, 1);
+    let wait_queue = WaitQueue::new();
+    assert!(!wait_queue.wait_if(|| false));
+    assert!(wait_queue.is_empty());
+    // taking two locks in both orders is reported once, without deadlocking
+    if cfg!(all(feature = "lockdep", debug_assertions)) {
+        let (first, second) = (SpinLock::new(()), SpinLock::new(()));
+        let reports = lockdep_reports();
+        drop((first.lock(), second.lock()));
+        assert_eq!(lockdep_reports(), reports);
+        let second_guard = second.lock();
+        drop((first.lock(), second_guard));
+        assert_eq!(lockdep_reports(), reports + 1);
+        let second_guard = second.lock();
+        drop((first.lock(), second_guard));
+        assert_eq!(lockdep_reports(), reports + 1);
+        // try_lock never waits, so taking the locks in the opposite order through it is fine
+        let (outer, inner) = (SpinLock::new(()), SpinLock::new(()));
+        drop((outer.lock(), inner.lock()));
+        let inner_guard = inner.lock();
+        drop((outer.try_lock().unwrap(), inner_guard));
+        assert_eq!(lockdep_reports(), reports + 1);
+    }
+    log::info!("Lock test passed!");
+}
@@ -0,0 +1,127 @@
+//! Sleeping lock module
+//! `Mutex` blocks the task while the lock is held elsewhere, instead of spinning
+//! `Condvar` lets a task holding a Mutex sleep until the guarded state changes
+
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::WaitQueue;
+use crate::task::current_task;
+
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    wait_queue: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            wait_queue: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Acquire the lock, block the current task while it is held by another one
+    /// without a current task, e.g. during boot, it spins instead
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            if current_task().is_some() {
+                self.wait_queue.wait_if(|| self.locked.load(Ordering::Relaxed));
+            } else {
+                spin_loop();
+            }
+        }
+    }
+
+    /// Acquire the lock if it is free, never blocks
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+}
+
+/// Access to the data of a Mutex, the lock is released and a waiter is woken up when it is dropped
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.wait_queue.wake_one();
+    }
+}
+
+pub struct Condvar {
+    wait_queue: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            wait_queue: WaitQueue::new(),
+        }
+    }
+
+    /// Release the Mutex and block until notified, the Mutex is acquired again before returning
+    /// spurious wake-ups are possible, so the condition must be checked in a loop
+    /// it must be called by a task, there is nothing to block otherwise
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        // unlock inside the wait queue lock, a notifier holding the Mutex can't slip in between
+        self.wait_queue.wait_if(move || {
+            drop(guard);
+            true
+        });
+        mutex.lock()
+    }
+
+    /// Block until `condition` is false, the Mutex is held while checking it
+    pub fn wait_while<'a, T>(
+        &self, mut guard: MutexGuard<'a, T>, mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wake up one waiting task
+    pub fn notify_one(&self) -> bool {
+        self.wait_queue.wake_one()
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,54 @@
+//! Semaphore module
+//! A counting semaphore, tasks block in `acquire` while there is no permit left
+
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::WaitQueue;
+use crate::task::current_task;
+
+pub struct Semaphore {
+    permits: AtomicUsize,
+    wait_queue: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            permits: AtomicUsize::new(permits),
+            wait_queue: WaitQueue::new(),
+        }
+    }
+
+    /// Take a permit, block the current task until one is available
+    /// without a current task, e.g. during boot, it spins instead
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            if current_task().is_some() {
+                self.wait_queue.wait_if(|| self.permits.load(Ordering::Relaxed) == 0);
+            } else {
+                spin_loop();
+            }
+        }
+    }
+
+    /// Take a permit if one is available, never blocks
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| permits.checked_sub(1))
+            .is_ok()
+    }
+
+    /// Return a permit and wake up a waiting task
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.wait_queue.wake_one();
+    }
+
+    /// Number of permits available
+    pub fn permits(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,172 @@
+//! Spin lock module
+//! `SpinLock` busy-waits until the lock is free, `IrqSpinLock` also keeps interrupts disabled
+//! on the hart while it is held, so it can be shared with interrupt handlers
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    hint::spin_loop,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    panic::Location,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::{
+    irq::{pop_off, push_off},
+    lockdep::LockClass,
+};
+use crate::cpu::hart_id;
+
+/// Owner of a lock which is free
+const NO_OWNER: usize = usize::MAX;
+
+/// A spin lock which records the hart holding it,
+/// taking it again on the same hart is a deadlock and panics instead of hanging
+pub struct SpinLock<T> {
+    owner: AtomicUsize,
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            owner: AtomicUsize::new(NO_OWNER),
+            class: LockClass::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Spin until the lock is acquired
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let hart = hart_id();
+        self.class.acquire(Location::caller());
+        loop {
+            match self
+                .owner
+                .compare_exchange_weak(NO_OWNER, hart, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return SpinLockGuard { lock: self },
+                Err(owner) if owner == hart => panic!("Deadlock: the lock is already held by hart {}", hart),
+                Err(_) => spin_loop(),
+            }
+        }
+    }
+
+    /// Acquire the lock if it is free
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.owner
+            .compare_exchange(NO_OWNER, hart_id(), Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        self.class.try_acquired(Location::caller());
+        Some(SpinLockGuard { lock: self })
+    }
+
+    /// Check if the lock is held by the running hart
+    pub fn is_held_by_current_hart(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == hart_id()
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> fmt::Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.owner.load(Ordering::Relaxed) {
+            NO_OWNER => write!(f, "SpinLock {{ unlocked }}"),
+            owner => write!(f, "SpinLock {{ locked by hart {} }}", owner),
+        }
+    }
+}
+
+/// Access to the data of a SpinLock, the lock is released when it is dropped
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.class.release();
+        self.lock.owner.store(NO_OWNER, Ordering::Release);
+    }
+}
+
+/// A spin lock which disables interrupts on the hart while it is held
+pub struct IrqSpinLock<T> {
+    inner: SpinLock<T>,
+}
+
+impl<T> IrqSpinLock<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: SpinLock::new(data),
+        }
+    }
+
+    /// Check if the lock is held by the running hart
+    pub fn is_held_by_current_hart(&self) -> bool {
+        self.inner.is_held_by_current_hart()
+    }
+
+    /// Disable interrupts, then spin until the lock is acquired
+    #[track_caller]
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        push_off();
+        IrqSpinLockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+        }
+    }
+}
+
+/// Access to the data of an IrqSpinLock, interrupts are restored after the lock is released
+pub struct IrqSpinLockGuard<'a, T> {
+    guard: ManuallyDrop<SpinLockGuard<'a, T>>,
+}
+
+impl<T> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // release the lock before interrupts may be enabled again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        pop_off();
+    }
+}
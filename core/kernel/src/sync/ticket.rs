@@ -0,0 +1,73 @@
+//! Ticket lock module
+//! A fair spin lock: harts take a ticket and are served in the order they arrived
+
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    panic::Location,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::lockdep::LockClass;
+
+pub struct TicketLock<T> {
+    /// The ticket given to the next hart
+    next_ticket: AtomicUsize,
+    /// The ticket allowed to hold the lock
+    now_serving: AtomicUsize,
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for TicketLock<T> {}
+unsafe impl<T: Send> Send for TicketLock<T> {}
+
+impl<T> TicketLock<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            class: LockClass::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Take a ticket and spin until it is served
+    #[track_caller]
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        self.class.acquire(Location::caller());
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            spin_loop();
+        }
+        TicketLockGuard { lock: self }
+    }
+}
+
+/// Access to the data of a TicketLock, the next ticket is served when it is dropped
+pub struct TicketLockGuard<'a, T> {
+    lock: &'a TicketLock<T>,
+}
+
+impl<T> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.class.release();
+        self.lock.now_serving.fetch_add(1, Ordering::Release);
+    }
+}
@@ -0,0 +1,60 @@
+//! Wait queue module
+//! Tasks park on a wait queue in the Blocked status and are woken up in FIFO order
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use super::IrqSpinLock;
+use crate::task::{TaskControlBlock, block_current_and_run_next, wakeup_task};
+
+pub struct WaitQueue {
+    /// It may be woken up from interrupt handlers, e.g. timer callbacks
+    queue: IrqSpinLock<VecDeque<Arc<TaskControlBlock>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            queue: IrqSpinLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Block the current task if `condition` holds, return false if it didn't block
+    /// `condition` is checked with the queue locked, so a waker which changes the state before
+    /// waking up can't be missed
+    pub fn wait_if(&self, condition: impl FnOnce() -> bool) -> bool {
+        let mut queue = self.queue.lock();
+        if !condition() {
+            return false;
+        }
+        // the queue is unlocked when the task is enqueued, before switching away
+        // a waker may find the task before it switches out, `wakeup_task` handles that
+        block_current_and_run_next(move |task| queue.push_back(task));
+        true
+    }
+
+    /// Wake up the first waiting task, return false if there is none
+    pub fn wake_one(&self) -> bool {
+        let task = self.queue.lock().pop_front();
+        task.map(wakeup_task).is_some()
+    }
+
+    /// Wake up all waiting tasks, return how many were woken up
+    pub fn wake_all(&self) -> usize {
+        let tasks = core::mem::take(&mut *self.queue.lock());
+        let count = tasks.len();
+        tasks.into_iter().for_each(|task| {
+            wakeup_task(task);
+        });
+        count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.lock().is_empty()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
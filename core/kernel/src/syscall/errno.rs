@@ -0,0 +1,41 @@
+//! Error numbers of the Linux ABI, syscalls return them negated in a0
+
+use crate::memory::AllocError;
+
+/// Linux errno
+#[allow(clippy::upper_case_acronyms)]
+#[repr(isize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    /// No such file or directory
+    ENOENT = 2,
+    /// Argument list too long
+    E2BIG = 7,
+    /// Exec format error
+    ENOEXEC = 8,
+    /// Bad file descriptor
+    EBADF = 9,
+    /// No child processes
+    ECHILD = 10,
+    /// Out of memory
+    ENOMEM = 12,
+    /// Bad address
+    EFAULT = 14,
+    /// File exists
+    EEXIST = 17,
+    /// Invalid argument
+    EINVAL = 22,
+    /// File name too long
+    ENAMETOOLONG = 36,
+    /// Function not implemented
+    ENOSYS = 38,
+}
+
+/// Result of a syscall, Ok(value) is returned as is and Err(errno) as -errno
+pub type SyscallResult = Result<usize, Errno>;
+
+impl From<AllocError> for Errno {
+    fn from(_: AllocError) -> Self {
+        Errno::ENOMEM
+    }
+}
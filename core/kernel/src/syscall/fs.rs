@@ -0,0 +1,94 @@
+//! File and console related syscalls
+
+use alloc::{string::String, vec, vec::Vec};
+use core::str;
+
+use super::errno::{Errno, SyscallResult};
+use crate::{
+    config::{PAGE_SIZE, USER_SPACE_END},
+    console,
+    memory::{copy_from_user, copy_to_user},
+    task::{current_task, suspend_current_and_run_next},
+};
+
+const FD_STDIN: usize = 0;
+const FD_STDOUT: usize = 1;
+const FD_STDERR: usize = 2;
+/// Max length of a char encoded in UTF-8
+const UTF8_CHAR_MAX: usize = 4;
+
+/// Read up to `len` bytes from the file `fd` into `buf`, only stdin is supported now
+/// it waits until at least one byte is available, and reads at most a page at once
+pub fn sys_read(fd: usize, buf: *mut u8, len: usize) -> SyscallResult {
+    if fd != FD_STDIN {
+        return Err(Errno::EBADF);
+    }
+    if len == 0 {
+        return Ok(0);
+    }
+    // `len` comes from user space, don't let it size a kernel allocation
+    let mut bytes = vec![0u8; len.min(PAGE_SIZE)];
+    let count = loop {
+        match console::read(&mut bytes) {
+            0 if console::wait_for_input() => {}
+            0 => suspend_current_and_run_next(),
+            count => break count,
+        }
+    };
+    let task = current_task().expect("No current task");
+    copy_to_user(&mut task.inner_exclusive_access().memory_space, buf, &bytes[..count])?;
+    Ok(count)
+}
+
+/// Write `len` bytes of `buf` to the file `fd`, only stdout and stderr are supported now
+/// the bytes are copied and written a page at a time, a fault after the first page ends the write early
+pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> SyscallResult {
+    if fd != FD_STDOUT && fd != FD_STDERR {
+        return Err(Errno::EBADF);
+    }
+    if (buf as usize).checked_add(len).is_none_or(|end| end > USER_SPACE_END) {
+        return Err(Errno::EFAULT);
+    }
+    let task = current_task().expect("No current task");
+    // `len` comes from user space, don't let it size a kernel allocation
+    let mut bytes = Vec::with_capacity(len.min(PAGE_SIZE) + UTF8_CHAR_MAX - 1);
+    let mut written = 0;
+    while written < len {
+        let count = (len - written).min(PAGE_SIZE);
+        let start = bytes.len();
+        bytes.resize(start + count, 0);
+        let result = copy_from_user(
+            &mut task.inner_exclusive_access().memory_space,
+            buf.wrapping_add(written),
+            &mut bytes[start..],
+        );
+        if let Err(err) = result {
+            if written == 0 {
+                return Err(err);
+            }
+            bytes.truncate(start);
+            break;
+        }
+        written += count;
+        // a multi-byte char may cross the page boundary, its first bytes wait for the next page
+        let keep = if written < len { incomplete_char_len(&bytes) } else { 0 };
+        print!("{}", String::from_utf8_lossy(&bytes[..bytes.len() - keep]));
+        bytes.drain(..bytes.len() - keep);
+    }
+    if !bytes.is_empty() {
+        print!("{}", String::from_utf8_lossy(&bytes));
+    }
+    Ok(written)
+}
+
+/// Length of the incomplete char at the end of `bytes`, which the following bytes may complete
+fn incomplete_char_len(bytes: &[u8]) -> usize {
+    bytes
+        .utf8_chunks()
+        .last()
+        .map_or(0, |chunk| match str::from_utf8(chunk.invalid()) {
+            // an incomplete char has no error length, an invalid one has
+            Err(err) if err.error_len().is_none() => chunk.invalid().len(),
+            _ => 0,
+        })
+}
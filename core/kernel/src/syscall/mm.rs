@@ -0,0 +1,90 @@
+//! Memory management syscalls, only anonymous private mappings are supported
+
+use super::errno::{Errno, SyscallResult};
+use crate::{
+    config::{PAGE_SIZE, USER_SPACE_END},
+    memory::{MapPermission, VirtAddr},
+    task::current_task,
+};
+
+const PROT_READ: usize = 0x1;
+const PROT_WRITE: usize = 0x2;
+const PROT_EXEC: usize = 0x4;
+
+const MAP_SHARED: usize = 0x01;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+
+/// Convert PROT_* to the permission of a user area
+fn prot_to_perm(prot: usize) -> Result<MapPermission, Errno> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let mut perm = MapPermission::U;
+    if prot & PROT_READ != 0 {
+        perm |= MapPermission::R;
+    }
+    if prot & PROT_WRITE != 0 {
+        perm |= MapPermission::W;
+    }
+    if prot & PROT_EXEC != 0 {
+        perm |= MapPermission::X;
+    }
+    Ok(perm)
+}
+
+/// Check that [addr, addr + len) is a page aligned, non-empty range in user space
+fn check_range(addr: usize, len: usize) -> Result<(), Errno> {
+    if !addr.is_multiple_of(PAGE_SIZE) || len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    match addr.checked_add(len) {
+        Some(end) if end <= USER_SPACE_END => Ok(()),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+/// Map an anonymous private area, the pages are filled on the first access
+pub fn sys_mmap(addr: usize, len: usize, prot: usize, flags: usize, _fd: usize, _offset: usize) -> SyscallResult {
+    if flags & MAP_ANONYMOUS == 0 {
+        // there is no file to map yet
+        return Err(Errno::EBADF);
+    }
+    if flags & (MAP_SHARED | MAP_PRIVATE) != MAP_PRIVATE || len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let perm = prot_to_perm(prot)?;
+    let fixed = flags & MAP_FIXED != 0;
+    if fixed {
+        check_range(addr, len)?;
+    } else if addr.checked_add(len).is_none_or(|end| end > USER_SPACE_END) {
+        return Err(Errno::ENOMEM);
+    }
+    let task = current_task().expect("No current task");
+    let mut task_inner = task.inner_exclusive_access();
+    task_inner.memory_space.mmap(addr, len, perm, fixed)
+}
+
+/// Unmap [addr, addr + len), unmapped pages in the range are ignored
+pub fn sys_munmap(addr: usize, len: usize) -> SyscallResult {
+    check_range(addr, len)?;
+    let task = current_task().expect("No current task");
+    let mut task_inner = task.inner_exclusive_access();
+    task_inner
+        .memory_space
+        .munmap(VirtAddr::from(addr).floor(), VirtAddr::from(addr + len).ceil());
+    Ok(0)
+}
+
+/// Change the permission of [addr, addr + len)
+pub fn sys_mprotect(addr: usize, len: usize, prot: usize) -> SyscallResult {
+    check_range(addr, len)?;
+    let perm = prot_to_perm(prot)?;
+    let task = current_task().expect("No current task");
+    let mut task_inner = task.inner_exclusive_access();
+    task_inner
+        .memory_space
+        .mprotect(VirtAddr::from(addr).floor(), VirtAddr::from(addr + len).ceil(), perm)?;
+    Ok(0)
+}
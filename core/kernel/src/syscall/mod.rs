@@ -0,0 +1,58 @@
+//! Syscall module
+//! Dispatch `ecall`s from U-mode by the syscall number in a7, following the Linux riscv64 ABI
+
+mod errno;
+mod fs;
+mod mm;
+mod process;
+
+pub use errno::Errno;
+use fs::*;
+use log::warn;
+use mm::*;
+use process::*;
+
+const SYSCALL_READ: usize = 63;
+const SYSCALL_WRITE: usize = 64;
+const SYSCALL_EXIT: usize = 93;
+const SYSCALL_EXIT_GROUP: usize = 94;
+const SYSCALL_NANOSLEEP: usize = 101;
+const SYSCALL_CLOCK_GETTIME: usize = 113;
+const SYSCALL_SCHED_YIELD: usize = 124;
+const SYSCALL_GETPID: usize = 172;
+const SYSCALL_BRK: usize = 214;
+const SYSCALL_MUNMAP: usize = 215;
+const SYSCALL_CLONE: usize = 220;
+const SYSCALL_EXECVE: usize = 221;
+const SYSCALL_MMAP: usize = 222;
+const SYSCALL_MPROTECT: usize = 226;
+const SYSCALL_WAIT4: usize = 260;
+
+/// Handle the syscall `id` with arguments a0 ~ a5, return the value to put in a0
+pub fn syscall(id: usize, args: [usize; 6]) -> isize {
+    let result = match id {
+        SYSCALL_READ => sys_read(args[0], args[1] as *mut u8, args[2]),
+        SYSCALL_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
+        // there is only one thread in a process, so exit_group is the same as exit
+        SYSCALL_EXIT | SYSCALL_EXIT_GROUP => sys_exit(args[0] as i32),
+        SYSCALL_NANOSLEEP => sys_nanosleep(args[0] as *const TimeSpec, args[1] as *mut TimeSpec),
+        SYSCALL_CLOCK_GETTIME => sys_clock_gettime(args[0], args[1] as *mut TimeSpec),
+        SYSCALL_SCHED_YIELD => sys_sched_yield(),
+        SYSCALL_GETPID => sys_getpid(),
+        SYSCALL_BRK => sys_brk(args[0]),
+        SYSCALL_MUNMAP => sys_munmap(args[0], args[1]),
+        SYSCALL_CLONE => sys_clone(args[0], args[1]),
+        SYSCALL_EXECVE => sys_execve(args[0] as *const u8, args[1] as *const usize, args[2] as *const usize),
+        SYSCALL_MMAP => sys_mmap(args[0], args[1], args[2], args[3], args[4], args[5]),
+        SYSCALL_MPROTECT => sys_mprotect(args[0], args[1], args[2]),
+        SYSCALL_WAIT4 => sys_wait4(args[0] as isize, args[1] as *mut i32, args[2]),
+        _ => {
+            warn!("[kernel] Unsupported syscall {}", id);
+            Err(Errno::ENOSYS)
+        }
+    };
+    match result {
+        Ok(value) => value as isize,
+        Err(errno) => -(errno as isize),
+    }
+}
@@ -0,0 +1,195 @@
+//! Process and time related syscalls
+
+use alloc::{string::String, vec::Vec};
+
+use super::errno::{Errno, SyscallResult};
+use crate::{
+    config::USER_STACK_SIZE,
+    loader::get_app_data_by_name,
+    memory::{MemorySpace, get_user, put_user, translated_str},
+    task::{
+        WaitResult, add_task, block_current_and_run_next, current_task, exit_current_and_run_next,
+        suspend_current_and_run_next, wakeup_task,
+    },
+    timer::{add_timer, get_time_ms, get_time_ns},
+};
+
+/// Signal sent to the parent when the child exits, the low byte of the clone flags
+const CSIGNAL: usize = 0xff;
+/// Return at once if no child has exited
+const WNOHANG: usize = 1;
+
+const CLOCK_REALTIME: usize = 0;
+const CLOCK_MONOTONIC: usize = 1;
+const NSEC_PER_SEC: usize = 1_000_000_000;
+const NSEC_PER_MSEC: usize = 1_000_000;
+const MSEC_PER_SEC: usize = 1_000;
+
+/// `struct timespec` of the Linux ABI
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TimeSpec {
+    pub tv_sec: isize,
+    pub tv_nsec: isize,
+}
+
+/// Exit the current task, it never returns
+pub fn sys_exit(exit_code: i32) -> ! {
+    exit_current_and_run_next(exit_code)
+}
+
+/// Give up the rest of the time slice
+pub fn sys_sched_yield() -> SyscallResult {
+    suspend_current_and_run_next();
+    Ok(0)
+}
+
+/// Create a child process, only the fork-like clone(no CLONE_* flag) is supported
+/// `stack` is the stack pointer of the child, 0 means the same as the parent
+pub fn sys_clone(flags: usize, stack: usize) -> SyscallResult {
+    if flags & !CSIGNAL != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let current = current_task().expect("No current task");
+    let child = current.fork()?;
+    let child_pid = child.getpid();
+    let trap_cx = child.inner_exclusive_access().get_trap_cx();
+    // fork returns 0 in the child
+    trap_cx.x[10] = 0;
+    if stack != 0 {
+        trap_cx.set_sp(stack);
+    }
+    add_task(child);
+    Ok(child_pid)
+}
+
+/// Replace the program of the current task with the app at `path`
+/// there is no filesystem yet, the apps linked into the kernel are in the root directory
+/// it returns argc in a0 to the new program, and keeps the old program if it fails
+pub fn sys_execve(path: *const u8, argv: *const usize, envp: *const usize) -> SyscallResult {
+    let task = current_task().expect("No current task");
+    let (path, argv, envp) = {
+        let mut task_inner = task.inner_exclusive_access();
+        let space = &mut task_inner.memory_space;
+        (
+            translated_str(space, path)?,
+            read_str_array(space, argv)?,
+            read_str_array(space, envp)?,
+        )
+    };
+    let elf_data = get_app_data_by_name(path.strip_prefix('/').unwrap_or(&path)).ok_or(Errno::ENOENT)?;
+    task.exec(elf_data, &argv, &envp)?;
+    Ok(argv.len())
+}
+
+/// Read a NULL-terminated array of string pointers from user space, a NULL array is empty
+fn read_str_array(space: &mut MemorySpace, mut ptr: *const usize) -> Result<Vec<String>, Errno> {
+    let mut strings = Vec::new();
+    if ptr.is_null() {
+        return Ok(strings);
+    }
+    loop {
+        let str_ptr = get_user(space, ptr)?;
+        if str_ptr == 0 {
+            return Ok(strings);
+        }
+        // more pointers than the user stack can hold are rejected by exec anyway
+        if strings.len() >= USER_STACK_SIZE / size_of::<usize>() {
+            return Err(Errno::E2BIG);
+        }
+        strings.push(translated_str(space, str_ptr as *const u8)?);
+        ptr = ptr.wrapping_add(1);
+    }
+}
+
+/// Wait for a child to exit and reap it, return its pid and store its exit status in `wstatus`
+/// pid -1 means any child, there are no process groups so pid 0 means any child too
+/// rusage is not supported
+pub fn sys_wait4(pid: isize, wstatus: *mut i32, options: usize) -> SyscallResult {
+    if options & !WNOHANG != 0 || pid < -1 {
+        return Err(Errno::EINVAL);
+    }
+    let pid = if pid == 0 { -1 } else { pid };
+    let task = current_task().expect("No current task");
+    loop {
+        let mut result = WaitResult::StillRunning;
+        if options & WNOHANG == 0 {
+            // an exiting child wakes us up after it becomes a zombie, it can't be missed
+            task.child_exit.wait_if(|| {
+                result = task.waitpid(pid);
+                result == WaitResult::StillRunning
+            });
+        } else {
+            result = task.waitpid(pid);
+        }
+        match result {
+            WaitResult::Exited { pid, exit_code } => {
+                if !wstatus.is_null() {
+                    let status = (exit_code & 0xff) << 8;
+                    put_user(&mut task.inner_exclusive_access().memory_space, wstatus, &status)?;
+                }
+                return Ok(pid);
+            }
+            WaitResult::NoChild => return Err(Errno::ECHILD),
+            WaitResult::StillRunning if options & WNOHANG != 0 => return Ok(0),
+            // woken up, check again
+            WaitResult::StillRunning => {}
+        }
+    }
+}
+
+pub fn sys_getpid() -> SyscallResult {
+    Ok(current_task().expect("No current task").getpid())
+}
+
+/// Set the program break, return the new break or the current one if it can't be changed
+/// brk(0) queries the current break
+pub fn sys_brk(addr: usize) -> SyscallResult {
+    let task = current_task().expect("No current task");
+    let mut task_inner = task.inner_exclusive_access();
+    Ok(task_inner.memory_space.brk(addr))
+}
+
+/// Get the time of `clock_id`, there is no RTC so the realtime clock starts at boot too
+pub fn sys_clock_gettime(clock_id: usize, tp: *mut TimeSpec) -> SyscallResult {
+    if clock_id != CLOCK_REALTIME && clock_id != CLOCK_MONOTONIC {
+        return Err(Errno::EINVAL);
+    }
+    let ns = get_time_ns();
+    let time = TimeSpec {
+        tv_sec: (ns / NSEC_PER_SEC) as isize,
+        tv_nsec: (ns % NSEC_PER_SEC) as isize,
+    };
+    let task = current_task().expect("No current task");
+    put_user(&mut task.inner_exclusive_access().memory_space, tp, &time)?;
+    Ok(0)
+}
+
+/// Sleep for the time in `req`, rounded up to milliseconds
+/// there are no signals, so the sleep is never interrupted and `rem` is never written
+pub fn sys_nanosleep(req: *const TimeSpec, _rem: *mut TimeSpec) -> SyscallResult {
+    let time = {
+        let task = current_task().expect("No current task");
+        get_user(&mut task.inner_exclusive_access().memory_space, req)?
+    };
+    if time.tv_sec < 0 || !(0..NSEC_PER_SEC as isize).contains(&time.tv_nsec) {
+        return Err(Errno::EINVAL);
+    }
+    let ms = (time.tv_sec as usize)
+        .saturating_mul(MSEC_PER_SEC)
+        .saturating_add((time.tv_nsec as usize).div_ceil(NSEC_PER_MSEC));
+    let deadline = get_time_ms().saturating_add(ms);
+    // the timer fires on a tick, which may be a little earlier than the deadline
+    loop {
+        let now = get_time_ms();
+        if now >= deadline {
+            break;
+        }
+        block_current_and_run_next(|task| {
+            add_timer(deadline - now, move || {
+                wakeup_task(task);
+            });
+        });
+    }
+    Ok(0)
+}
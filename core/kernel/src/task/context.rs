@@ -0,0 +1,36 @@
+//! Task context module
+//! The callee-saved registers saved by `__switch`
+
+use crate::trap::trap_return;
+
+/// Task context, the layout must be kept in sync with `__switch`
+#[repr(C)]
+#[derive(Debug)]
+pub struct TaskContext {
+    /// Return address, `__switch` returns to it
+    ra: usize,
+    /// Kernel stack pointer
+    sp: usize,
+    /// Callee-saved registers s0 ~ s11
+    s: [usize; 12],
+}
+
+impl TaskContext {
+    /// Create an empty task context
+    pub fn zero_init() -> Self {
+        Self {
+            ra: 0,
+            sp: 0,
+            s: [0; 12],
+        }
+    }
+
+    /// Create a task context which returns to U-mode through `trap_return` on the given kernel stack
+    pub fn goto_trap_return(kernel_stack_top: usize) -> Self {
+        Self {
+            ra: trap_return as usize,
+            sp: kernel_stack_top,
+            s: [0; 12],
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! Task manager module
+//! Hold the ready tasks in a FIFO run queue, which makes the scheduling round-robin
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use super::{TaskControlBlock, TaskStatus};
+use crate::sync::TicketLock;
+
+pub struct TaskManager {
+    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl TaskManager {
+    pub fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
+        }
+    }
+
+    /// Add a ready task to the tail of the run queue
+    pub fn add(&mut self, task: Arc<TaskControlBlock>) {
+        debug_assert_eq!(task.inner_exclusive_access().status(), TaskStatus::Ready);
+        self.ready_queue.push_back(task);
+    }
+
+    /// Take the task at the head of the run queue
+    pub fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.ready_queue.pop_front()
+    }
+}
+
+lazy_static! {
+    /// The run queue shared by all harts, a ticket lock keeps idle harts from starving each other
+    static ref TASK_MANAGER: TicketLock<TaskManager> = TicketLock::new(TaskManager::new());
+}
+
+/// Add a ready task to the run queue
+pub fn add_task(task: Arc<TaskControlBlock>) {
+    TASK_MANAGER.lock().add(task);
+}
+
+/// Fetch the next task to run
+pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
+    TASK_MANAGER.lock().fetch()
+}
@@ -0,0 +1,75 @@
+//! Task management module
+//! A task is a user process, it owns a memory space, a kernel stack and a PID
+//! Tasks are scheduled round-robin, the timer interrupt preempts the running one
+
+mod context;
+mod manager;
+mod pid;
+mod processor;
+mod switch;
+#[allow(clippy::module_inception)]
+mod task;
+mod user_stack;
+
+use alloc::sync::Arc;
+
+use context::TaskContext;
+pub use manager::add_task;
+pub use processor::{current_task, current_trap_cx, current_user_token, run_tasks, wakeup_task};
+use processor::{schedule, set_switched_out, take_current_task};
+pub use task::{TaskControlBlock, TaskStatus, WaitResult};
+
+use crate::sync::SpinLock;
+
+lazy_static! {
+    /// The first user task, orphaned tasks are handed over to it
+    static ref INITPROC: SpinLock<Option<Arc<TaskControlBlock>>> = SpinLock::new(None);
+}
+
+/// Load the initproc from an ELF image and add it to the run queue
+pub fn add_initproc(elf_data: &[u8]) {
+    let initproc = Arc::new(TaskControlBlock::new(elf_data));
+    *INITPROC.lock() = Some(initproc.clone());
+    add_task(initproc);
+}
+
+/// Put the current task back to the run queue and run the next one
+pub fn suspend_current_and_run_next() {
+    let task = take_current_task().expect("No current task to suspend");
+    let mut task_inner = task.inner_exclusive_access();
+    task_inner.set_status(TaskStatus::Ready);
+    let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
+    drop(task_inner);
+    set_switched_out(task);
+    schedule(task_cx_ptr);
+}
+
+/// Block the current task and run the next one, it runs again after `wakeup_task`
+/// `enqueue` makes the task reachable by its waker, the task is already Blocked then
+pub fn block_current_and_run_next(enqueue: impl FnOnce(Arc<TaskControlBlock>)) {
+    let task = take_current_task().expect("No current task to block");
+    let mut task_inner = task.inner_exclusive_access();
+    task_inner.set_status(TaskStatus::Blocked);
+    let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
+    drop(task_inner);
+    enqueue(task.clone());
+    set_switched_out(task);
+    schedule(task_cx_ptr);
+}
+
+/// Exit the current task with `exit_code` and run the next one
+pub fn exit_current_and_run_next(exit_code: i32) -> ! {
+    let task = take_current_task().expect("No current task to exit");
+    let initproc = INITPROC.lock().clone();
+    let reaper = initproc.as_ref().filter(|initproc| !Arc::ptr_eq(initproc, &task));
+    task.exit(exit_code, reaper);
+    if reaper.is_none() && initproc.is_some() {
+        // the initproc itself exits, no one can reap it
+        INITPROC.lock().take();
+    }
+    set_switched_out(task);
+    // the context of an exited task is never restored
+    let mut unused = TaskContext::zero_init();
+    schedule(&mut unused as *mut _);
+    unreachable!("Exited task is scheduled again");
+}
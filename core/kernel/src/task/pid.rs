@@ -0,0 +1,112 @@
+//! PID and kernel stack module
+//! Allocate process identifiers and the kernel stack bound to each of them
+
+use alloc::vec::Vec;
+
+use crate::{
+    config::{KERNEL_STACK_SIZE, PAGE_SIZE, TRAMPOLINE},
+    memory::{AllocError, KERNEL_SPACE, MapPermission, VirtAddr},
+    sync::SpinLock,
+};
+
+/// Allocate PIDs incrementally and reuse the recycled ones first
+struct PidAllocator {
+    current: usize,
+    recycled: Vec<usize>,
+}
+
+impl PidAllocator {
+    fn new() -> Self {
+        Self {
+            current: 0,
+            recycled: Vec::new(),
+        }
+    }
+
+    fn alloc(&mut self) -> PidHandle {
+        if let Some(pid) = self.recycled.pop() {
+            PidHandle(pid)
+        } else {
+            self.current += 1;
+            PidHandle(self.current - 1)
+        }
+    }
+
+    /// Number of PIDs in use
+    fn active(&self) -> usize {
+        self.current - self.recycled.len()
+    }
+
+    fn dealloc(&mut self, pid: usize) {
+        debug_assert!(pid < self.current, "Dealloc an unallocated pid {}", pid);
+        debug_assert!(!self.recycled.contains(&pid), "Pid {} has been deallocated", pid);
+        self.recycled.push(pid);
+    }
+}
+
+lazy_static! {
+    static ref PID_ALLOCATOR: SpinLock<PidAllocator> = SpinLock::new(PidAllocator::new());
+}
+
+/// RAII handle of a PID, the PID is recycled when it is dropped
+#[derive(Debug)]
+pub struct PidHandle(pub usize);
+
+impl Drop for PidHandle {
+    fn drop(&mut self) {
+        PID_ALLOCATOR.lock().dealloc(self.0);
+    }
+}
+
+/// Allocate a new PID
+pub fn pid_alloc() -> PidHandle {
+    PID_ALLOCATOR.lock().alloc()
+}
+
+/// Get the number of PIDs in use, which is the number of tasks not yet released
+pub fn active_pids() -> usize {
+    PID_ALLOCATOR.lock().active()
+}
+
+/// Return (bottom, top) of the kernel stack of `pid` in kernel space
+/// kernel stacks are placed below the trampoline, separated by guard pages
+pub fn kernel_stack_position(pid: usize) -> (usize, usize) {
+    let top = TRAMPOLINE - pid * (KERNEL_STACK_SIZE + PAGE_SIZE);
+    let bottom = top - KERNEL_STACK_SIZE;
+    (bottom, top)
+}
+
+/// Kernel stack of a task, mapped in kernel space while the task is alive
+pub struct KernelStack {
+    pid: usize,
+}
+
+impl KernelStack {
+    /// Map the kernel stack of the given pid in kernel space
+    /// it is populated at once, a page fault in the kernel can't be resolved
+    pub fn new(pid_handle: &PidHandle) -> Result<Self, AllocError> {
+        let pid = pid_handle.0;
+        let (bottom, top) = kernel_stack_position(pid);
+        KERNEL_SPACE.lock().insert_populated_area(
+            VirtAddr::from(bottom),
+            VirtAddr::from(top),
+            MapPermission::R | MapPermission::W,
+        )?;
+        Ok(Self { pid })
+    }
+
+    /// Get the top address of the kernel stack
+    pub fn get_top(&self) -> usize {
+        let (_, top) = kernel_stack_position(self.pid);
+        top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let (bottom, _) = kernel_stack_position(self.pid);
+        KERNEL_SPACE
+            .lock()
+            .remove_area_with_start_vpn(VirtAddr::from(bottom).floor());
+    }
+}
@@ -0,0 +1,170 @@
+//! Processor module
+//! Track the task running on the hart and the idle control flow which picks the next task
+
+use alloc::sync::Arc;
+
+use log::info;
+use riscv::{
+    asm::{sfence_vma_all, wfi},
+    register::sstatus,
+};
+
+use super::{
+    TaskControlBlock, TaskStatus,
+    context::TaskContext,
+    manager::{add_task, fetch_task},
+    pid::active_pids,
+    switch::__switch,
+};
+use crate::{cpu::PerCpu, sbi::shutdown, sync::SpinLock, trap::TrapContext};
+
+pub struct Processor {
+    /// The task running on this hart
+    current: Option<Arc<TaskControlBlock>>,
+    /// The task which has just given up the hart, it is handled in the idle control flow
+    /// because its kernel stack is in use and its context is not saved until it switches away
+    switched_out: Option<Arc<TaskControlBlock>>,
+    /// Task context of the idle control flow
+    idle_task_cx: TaskContext,
+}
+
+impl Processor {
+    pub fn new() -> Self {
+        Self {
+            current: None,
+            switched_out: None,
+            idle_task_cx: TaskContext::zero_init(),
+        }
+    }
+
+    fn get_idle_task_cx_ptr(&mut self) -> *mut TaskContext {
+        &mut self.idle_task_cx as *mut _
+    }
+
+    pub fn take_current(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.current.take()
+    }
+
+    pub fn current(&self) -> Option<Arc<TaskControlBlock>> {
+        self.current.as_ref().map(Arc::clone)
+    }
+}
+
+lazy_static! {
+    /// The processor of each hart
+    static ref PROCESSOR: PerCpu<SpinLock<Processor>> = PerCpu::new(|_| SpinLock::new(Processor::new()));
+}
+
+/// The idle control flow, keep fetching ready tasks and switching to them
+/// shutdown when there is no task left
+/// every hart runs it, tasks are fetched from the shared run queue
+pub fn run_tasks() -> ! {
+    loop {
+        let mut processor = PROCESSOR.get().lock();
+        if let Some(task) = fetch_task() {
+            let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
+            let mut task_inner = task.inner_exclusive_access();
+            let next_task_cx_ptr = &task_inner.task_cx as *const TaskContext;
+            task_inner.set_status(TaskStatus::Running);
+            task_inner.on_cpu = true;
+            drop(task_inner);
+            processor.current = Some(task);
+            drop(processor);
+            unsafe {
+                // the kernel stack of the task may have been remapped since this hart last used its address
+                sfence_vma_all();
+                __switch(idle_task_cx_ptr, next_task_cx_ptr);
+            }
+            // back from the task
+            let switched_out = PROCESSOR.get().lock().switched_out.take();
+            if let Some(task) = switched_out {
+                finish_switch_out(task);
+            }
+        } else {
+            drop(processor);
+            if active_pids() == 0 {
+                info!("[kernel] No task left, shutting down");
+                shutdown(false);
+            }
+            wait_for_interrupt();
+        }
+    }
+}
+
+/// Sleep until an interrupt arrives, interrupts are only enabled while waiting
+fn wait_for_interrupt() {
+    unsafe {
+        sstatus::set_sie();
+        wfi();
+        sstatus::clear_sie();
+    }
+}
+
+/// Take the current task out of the processor
+pub fn take_current_task() -> Option<Arc<TaskControlBlock>> {
+    PROCESSOR.get().lock().take_current()
+}
+
+/// Get a reference of the current task
+pub fn current_task() -> Option<Arc<TaskControlBlock>> {
+    PROCESSOR.get().lock().current()
+}
+
+/// Get the satp token of the current task
+pub fn current_user_token() -> usize {
+    current_task()
+        .expect("No current task")
+        .inner_exclusive_access()
+        .get_user_token()
+}
+
+/// Get the trap context of the current task
+pub fn current_trap_cx() -> &'static mut TrapContext {
+    current_task()
+        .expect("No current task")
+        .inner_exclusive_access()
+        .get_trap_cx()
+}
+
+/// Keep the task which gives up the hart until the idle control flow takes over
+pub fn set_switched_out(task: Arc<TaskControlBlock>) {
+    PROCESSOR.get().lock().switched_out = Some(task);
+}
+
+/// The task is off the hart now: an exited task is released, a ready one(suspended,
+/// or woken up while switching out) goes back to the run queue and a blocked one waits for its waker
+fn finish_switch_out(task: Arc<TaskControlBlock>) {
+    let mut task_inner = task.inner_exclusive_access();
+    task_inner.on_cpu = false;
+    let ready = task_inner.status() == TaskStatus::Ready;
+    drop(task_inner);
+    if ready {
+        add_task(task);
+    }
+}
+
+/// Wake up a blocked task, return false if it is not blocked
+pub fn wakeup_task(task: Arc<TaskControlBlock>) -> bool {
+    let mut task_inner = task.inner_exclusive_access();
+    if task_inner.status() != TaskStatus::Blocked {
+        return false;
+    }
+    task_inner.set_status(TaskStatus::Ready);
+    // if it is still switching out, its hart puts it back to the run queue
+    let on_cpu = task_inner.on_cpu;
+    drop(task_inner);
+    if !on_cpu {
+        add_task(task);
+    }
+    true
+}
+
+/// Switch from the current task to the idle control flow
+pub fn schedule(switched_task_cx_ptr: *mut TaskContext) {
+    let mut processor = PROCESSOR.get().lock();
+    let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
+    drop(processor);
+    unsafe {
+        __switch(switched_task_cx_ptr, idle_task_cx_ptr);
+    }
+}
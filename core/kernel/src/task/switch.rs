@@ -0,0 +1,12 @@
+//! Wrap `__switch` in switch.S
+
+use core::arch::global_asm;
+
+use super::context::TaskContext;
+
+global_asm!(include_str!("switch.S"));
+
+unsafe extern "C" {
+    /// Switch to the control flow of `next_task_cx_ptr`, the current one is saved in `current_task_cx_ptr`
+    pub fn __switch(current_task_cx_ptr: *mut TaskContext, next_task_cx_ptr: *const TaskContext);
+}
@@ -0,0 +1,276 @@
+//! Task control block module
+//! Define the TaskControlBlock which owns everything a user process needs to run
+
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::mem;
+
+use super::{
+    context::TaskContext,
+    pid::{KernelStack, PidHandle, pid_alloc},
+    user_stack::init_user_stack,
+};
+use crate::{
+    config::TRAP_CONTEXT,
+    memory::{MemorySpace, PhysPageNum, VirtAddr, kernel_satp},
+    sync::{SpinLock, SpinLockGuard, WaitQueue},
+    syscall::Errno,
+    trap::{TrapContext, trap_handler},
+};
+
+/// Status of a task
+/// Ready -> Running -> Ready/Blocked/Zombie, Blocked -> Ready
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TaskStatus {
+    /// Waiting in the run queue
+    Ready,
+    /// Running on a hart
+    Running,
+    /// Waiting for an event, must not be scheduled
+    Blocked,
+    /// Exited but not yet reaped by its parent
+    Zombie,
+}
+
+impl TaskStatus {
+    /// Check if a task in this status is allowed to move to `next`
+    pub fn can_transition_to(self, next: TaskStatus) -> bool {
+        use TaskStatus::*;
+        matches!(
+            (self, next),
+            (Ready, Running) | (Running, Ready) | (Running, Blocked) | (Running, Zombie) | (Blocked, Ready)
+        )
+    }
+}
+
+/// Result of waiting for a child
+#[derive(Debug, PartialEq, Eq)]
+pub enum WaitResult {
+    /// A zombie child was reaped
+    Exited { pid: usize, exit_code: i32 },
+    /// Matching children exist, but none of them has exited
+    StillRunning,
+    /// No child matches the given pid
+    NoChild,
+}
+
+pub struct TaskControlBlock {
+    // immutable
+    pub pid: PidHandle,
+    pub kernel_stack: KernelStack,
+    /// Woken up when a child becomes a zombie
+    pub child_exit: WaitQueue,
+    // mutable
+    inner: SpinLock<TaskControlBlockInner>,
+}
+
+pub struct TaskControlBlockInner {
+    pub trap_cx_ppn: PhysPageNum,
+    pub task_cx: TaskContext,
+    pub memory_space: MemorySpace,
+    pub parent: Option<Weak<TaskControlBlock>>,
+    pub children: Vec<Arc<TaskControlBlock>>,
+    pub exit_code: i32,
+    task_status: TaskStatus,
+    /// Set from switching to the task until its hart has switched away from it
+    pub on_cpu: bool,
+}
+
+impl TaskControlBlockInner {
+    pub fn get_trap_cx(&self) -> &'static mut TrapContext {
+        self.trap_cx_ppn.get_mut()
+    }
+
+    pub fn get_user_token(&self) -> usize {
+        self.memory_space.satp_token()
+    }
+
+    pub fn status(&self) -> TaskStatus {
+        self.task_status
+    }
+
+    /// Move the task to a new status, invalid transitions are bugs
+    pub fn set_status(&mut self, status: TaskStatus) {
+        debug_assert!(
+            self.task_status.can_transition_to(status),
+            "Invalid task status transition: {:?} -> {:?}",
+            self.task_status,
+            status
+        );
+        self.task_status = status;
+    }
+
+    pub fn is_zombie(&self) -> bool {
+        self.task_status == TaskStatus::Zombie
+    }
+}
+
+impl TaskControlBlock {
+    /// Create a new task from an ELF image, the task is ready to run
+    pub fn new(elf_data: &[u8]) -> Self {
+        let (mut memory_space, user_sp, elf_info) =
+            MemorySpace::from_elf(elf_data).unwrap_or_else(|err| panic!("Failed to load the ELF: {:?}", err));
+        let (user_sp, _) = init_user_stack(&mut memory_space, user_sp, &[], &[], &elf_info)
+            .expect("Empty arguments always fit in the user stack");
+        let trap_cx_ppn = memory_space
+            .translate(VirtAddr::from(TRAP_CONTEXT).into())
+            .expect("TrapContext page is not mapped");
+        // alloc a pid and a kernel stack in kernel space
+        let pid = pid_alloc();
+        let kernel_stack = KernelStack::new(&pid).expect("Failed to map the kernel stack");
+        let kernel_stack_top = kernel_stack.get_top();
+        let task = Self {
+            pid,
+            kernel_stack,
+            child_exit: WaitQueue::new(),
+            inner: SpinLock::new(TaskControlBlockInner {
+                trap_cx_ppn,
+                task_cx: TaskContext::goto_trap_return(kernel_stack_top),
+                memory_space,
+                parent: None,
+                children: Vec::new(),
+                exit_code: 0,
+                task_status: TaskStatus::Ready,
+                on_cpu: false,
+            }),
+        };
+        *task.inner_exclusive_access().get_trap_cx() = TrapContext::app_init_context(
+            elf_info.entry,
+            user_sp,
+            kernel_satp(),
+            kernel_stack_top,
+            trap_handler as usize,
+        );
+        task
+    }
+
+    /// Replace the memory space of the task with a new one loaded from an ELF image
+    /// `argv` and `envp` are copied onto the new user stack, the old space is kept if it fails
+    pub fn exec(&self, elf_data: &[u8], argv: &[String], envp: &[String]) -> Result<(), Errno> {
+        let (mut memory_space, user_sp, elf_info) = MemorySpace::from_elf(elf_data)?;
+        let (user_sp, argv_base) = init_user_stack(&mut memory_space, user_sp, argv, envp, &elf_info)?;
+        let trap_cx_ppn = memory_space
+            .translate(VirtAddr::from(TRAP_CONTEXT).into())
+            .expect("TrapContext page is not mapped");
+        let mut inner = self.inner_exclusive_access();
+        inner.memory_space.recycle_data_pages();
+        inner.memory_space = memory_space;
+        inner.trap_cx_ppn = trap_cx_ppn;
+        let trap_cx = inner.get_trap_cx();
+        *trap_cx = TrapContext::app_init_context(
+            elf_info.entry,
+            user_sp,
+            kernel_satp(),
+            self.kernel_stack.get_top(),
+            trap_handler as usize,
+        );
+        // a0 = argc, a1 = argv, for programs which don't read them from the stack
+        trap_cx.x[10] = argv.len();
+        trap_cx.x[11] = argv_base;
+        Ok(())
+    }
+
+    /// Fork a child task, its memory is shared with the parent copy-on-write
+    /// the child returns to U-mode at the same place with the same registers
+    /// return ENOMEM if there is no memory for the child
+    pub fn fork(self: &Arc<Self>) -> Result<Arc<Self>, Errno> {
+        let mut parent_inner = self.inner_exclusive_access();
+        let memory_space = MemorySpace::from_existed_user(&mut parent_inner.memory_space)?;
+        let trap_cx_ppn = memory_space
+            .translate(VirtAddr::from(TRAP_CONTEXT).into())
+            .expect("TrapContext page is not mapped");
+        let pid = pid_alloc();
+        let kernel_stack = KernelStack::new(&pid)?;
+        let kernel_stack_top = kernel_stack.get_top();
+        let child = Arc::new(Self {
+            pid,
+            kernel_stack,
+            child_exit: WaitQueue::new(),
+            inner: SpinLock::new(TaskControlBlockInner {
+                trap_cx_ppn,
+                task_cx: TaskContext::goto_trap_return(kernel_stack_top),
+                memory_space,
+                parent: Some(Arc::downgrade(self)),
+                children: Vec::new(),
+                exit_code: 0,
+                task_status: TaskStatus::Ready,
+                on_cpu: false,
+            }),
+        });
+        parent_inner.children.push(child.clone());
+        // the trap context is copied from the parent, except the kernel stack
+        child.inner_exclusive_access().get_trap_cx().kernel_sp = kernel_stack_top;
+        Ok(child)
+    }
+
+    #[track_caller]
+    pub fn inner_exclusive_access(&self) -> SpinLockGuard<'_, TaskControlBlockInner> {
+        self.inner.lock()
+    }
+
+    pub fn getpid(&self) -> usize {
+        self.pid.0
+    }
+
+    /// Make `child` a child of this task
+    pub fn add_child(self: &Arc<Self>, child: Arc<TaskControlBlock>) {
+        child.inner_exclusive_access().parent = Some(Arc::downgrade(self));
+        self.inner_exclusive_access().children.push(child);
+    }
+
+    /// Turn the task into a zombie with `exit_code`
+    /// its children are handed over to `reaper`(normally the initproc), or orphaned if there is none,
+    /// and its user memory is released, the rest is released when the parent reaps it
+    pub fn exit(&self, exit_code: i32, reaper: Option<&Arc<TaskControlBlock>>) {
+        let mut inner = self.inner_exclusive_access();
+        inner.set_status(TaskStatus::Zombie);
+        inner.exit_code = exit_code;
+        let children = mem::take(&mut inner.children);
+        let parent = inner.parent.as_ref().and_then(Weak::upgrade);
+        inner.memory_space.recycle_data_pages();
+        // the reaper may be waiting on another hart with its own lock held, which then locks this task
+        drop(inner);
+        let has_children = !children.is_empty();
+        for child in children {
+            match reaper {
+                Some(reaper) => reaper.add_child(child),
+                None => child.inner_exclusive_access().parent = None,
+            }
+        }
+        if let Some(parent) = parent {
+            parent.child_exit.wake_all();
+        }
+        // some of the handed over children may be zombies already
+        if let Some(reaper) = reaper.filter(|_| has_children) {
+            reaper.child_exit.wake_all();
+        }
+    }
+
+    /// Reap a zombie child
+    /// pid == -1 means any child, otherwise only the child with the given pid
+    pub fn waitpid(&self, pid: isize) -> WaitResult {
+        let mut inner = self.inner_exclusive_access();
+        let matches = |child: &Arc<TaskControlBlock>| pid == -1 || pid as usize == child.getpid();
+        if !inner.children.iter().any(matches) {
+            return WaitResult::NoChild;
+        }
+        let zombie = inner
+            .children
+            .iter()
+            .position(|child| matches(child) && child.inner_exclusive_access().is_zombie());
+        match zombie {
+            Some(idx) => {
+                let child = inner.children.remove(idx);
+                let exit_code = child.inner_exclusive_access().exit_code;
+                WaitResult::Exited {
+                    pid: child.getpid(),
+                    exit_code,
+                }
+            }
+            None => WaitResult::StillRunning,
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Initial user stack module
+//! Lay out argc, argv, envp and the auxiliary vector on a new user stack, as the Linux ABI does
+//!
+//! ```text
+//! user_sp -> +---------------------------+
+//!            | envp / argv strings       |
+//!            | 16 random bytes           |
+//!            +---------------------------+ <- aligned to 16
+//!            | auxv pairs, AT_NULL       |
+//!            | envp pointers, NULL       |
+//!            | argv pointers, NULL       |
+//! sp      -> | argc                      |
+//!            +---------------------------+
+//! ```
+
+use alloc::{string::String, vec::Vec};
+use core::mem::size_of;
+
+use crate::{
+    config::{PAGE_SIZE, USER_STACK_SIZE},
+    memory::{ElfInfo, MemorySpace, copy_to_user},
+    syscall::Errno,
+    timer::get_time,
+};
+
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+const AT_RANDOM: usize = 25;
+
+/// Push the arguments, environment and auxiliary vector onto the stack below `user_sp`
+/// returns the new stack pointer(pointing to argc) and the address of argv
+pub fn init_user_stack(
+    space: &mut MemorySpace, user_sp: usize, argv: &[String], envp: &[String], elf_info: &ElfInfo,
+) -> Result<(usize, usize), Errno> {
+    let strings_len: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    // argc, argv and envp with their NULLs, 6 auxv pairs and AT_NULL
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 7 * 2;
+    if strings_len + 16 + words * size_of::<usize>() + 16 > USER_STACK_SIZE {
+        return Err(Errno::E2BIG);
+    }
+    let mut sp = user_sp;
+    let mut push_str = |space: &mut MemorySpace, s: &String| -> Result<usize, Errno> {
+        sp -= s.len() + 1;
+        copy_to_user(space, sp as *mut u8, s.as_bytes())?;
+        copy_to_user(space, (sp + s.len()) as *mut u8, &[0])?;
+        Ok(sp)
+    };
+    let envp_ptrs = envp.iter().map(|s| push_str(space, s)).collect::<Result<Vec<_>, _>>()?;
+    let argv_ptrs = argv.iter().map(|s| push_str(space, s)).collect::<Result<Vec<_>, _>>()?;
+    sp -= 16;
+    let random = sp;
+    copy_to_user(space, random as *mut u8, &random_bytes())?;
+
+    let auxv = [
+        (AT_PHDR, elf_info.phdr),
+        (AT_PHENT, elf_info.phent),
+        (AT_PHNUM, elf_info.phnum),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, elf_info.entry),
+        (AT_RANDOM, random),
+        (AT_NULL, 0),
+    ];
+    let mut table = Vec::with_capacity(words);
+    table.push(argv.len());
+    table.extend(&argv_ptrs);
+    table.push(0);
+    table.extend(&envp_ptrs);
+    table.push(0);
+    for (key, value) in auxv {
+        table.push(key);
+        table.push(value);
+    }
+    // sp must be 16-byte aligned at the entry
+    sp = (sp - table.len() * size_of::<usize>()) & !0xf;
+    let bytes = unsafe { core::slice::from_raw_parts(table.as_ptr() as *const u8, table.len() * size_of::<usize>()) };
+    copy_to_user(space, sp as *mut u8, bytes)?;
+    Ok((sp, sp + size_of::<usize>()))
+}
+
+/// Bytes for AT_RANDOM, there is no entropy source so they are derived from the time
+fn random_bytes() -> [u8; 16] {
+    let mut state = get_time() as u64 | 1;
+    let mut bytes = [0u8; 16];
+    for chunk in bytes.chunks_mut(8) {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        chunk.copy_from_slice(&state.to_le_bytes());
+    }
+    bytes
+}
@@ -0,0 +1,122 @@
+//! Timer module
+//! Program the SBI timer to generate ticks and provide the kernel clock
+
+mod wheel;
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::info;
+use riscv::register::{sie, time};
+pub use wheel::TimerId;
+use wheel::{TimerWheel, timer_wheel_test};
+
+use crate::{
+    board::CLCOK_FREQ, config::TICKS_PER_SEC, cpu::is_boot_hart, machine::machine_info, sbi::set_timer,
+    sync::IrqSpinLock,
+};
+
+const NSEC_PER_SEC: usize = 1_000_000_000;
+const MSEC_PER_SEC: usize = 1_000;
+
+/// Frequency of the `time` register, the timebase frequency of the device tree or CLCOK_FREQ of the board
+static CLOCK_FREQ: AtomicUsize = AtomicUsize::new(CLCOK_FREQ);
+
+/// Number of timer interrupts since the timer was initialized
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// The kernel timer wheel, driven by the timer interrupt
+    static ref TIMER_WHEEL: IrqSpinLock<TimerWheel> = IrqSpinLock::new(TimerWheel::new());
+}
+
+/// Initialize the timer, enable the timer interrupt and arm the first tick
+pub fn init() {
+    // a clock slower than a millisecond can't be counted in milliseconds
+    if let Some(freq) = machine_info().timebase_frequency.filter(|&freq| freq >= MSEC_PER_SEC) {
+        CLOCK_FREQ.store(freq, Ordering::Relaxed);
+    }
+    info!(
+        "Initializing timer, {} ticks per second, clock at {} Hz",
+        TICKS_PER_SEC,
+        clock_freq()
+    );
+    timer_wheel_test();
+    unsafe {
+        sie::set_stimer();
+    }
+    set_next_trigger();
+}
+
+/// Enable the timer interrupt on a secondary hart, it only drives preemption on that hart
+pub fn init_secondary() {
+    unsafe {
+        sie::set_stimer();
+    }
+    set_next_trigger();
+}
+
+/// Get the frequency of the `time` register
+fn clock_freq() -> usize {
+    CLOCK_FREQ.load(Ordering::Relaxed)
+}
+
+/// Get the raw value of the `time` register, it increases `clock_freq()` times per second
+pub fn get_time() -> usize {
+    time::read()
+}
+
+/// Convert raw clock cycles to nanoseconds without overflowing
+fn cycles_to_ns(cycles: usize) -> usize {
+    let freq = clock_freq();
+    cycles / freq * NSEC_PER_SEC + cycles % freq * NSEC_PER_SEC / freq
+}
+
+/// Get the monotonic time since boot in nanoseconds
+pub fn get_time_ns() -> usize {
+    cycles_to_ns(get_time())
+}
+
+/// Get the monotonic time since boot in milliseconds
+pub fn get_time_ms() -> usize {
+    get_time() / (clock_freq() / MSEC_PER_SEC)
+}
+
+/// Get the number of ticks since the timer was initialized
+#[allow(unused)]
+pub fn get_ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Arm the timer interrupt for the next tick
+pub fn set_next_trigger() {
+    set_timer(get_time() + clock_freq() / TICKS_PER_SEC);
+}
+
+/// Schedule `callback` to run in the timer interrupt after `delay_ms` milliseconds
+/// the delay is rounded up to whole ticks, a delay too long to count saturates
+pub fn add_timer(delay_ms: usize, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    let delay_ticks = delay_ms.saturating_mul(TICKS_PER_SEC).div_ceil(MSEC_PER_SEC);
+    TIMER_WHEEL.lock().add(delay_ticks, Box::new(callback))
+}
+
+/// Cancel a pending timer, return false if it has already fired
+#[allow(unused)]
+pub fn cancel_timer(id: TimerId) -> bool {
+    TIMER_WHEEL.lock().cancel(id)
+}
+
+/// Handle the timer interrupt: count the tick, arm the next one and run the expired timers
+/// every hart has its own timer, but only the boot hart advances the clock
+pub fn handle_timer_interrupt() {
+    set_next_trigger();
+    if !is_boot_hart() {
+        return;
+    }
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    // take the expired callbacks out first, so they can add new timers
+    let expired = TIMER_WHEEL.lock().advance();
+    for callback in expired {
+        callback();
+    }
+}
@@ -0,0 +1,106 @@
+//! Timer wheel module
+//! A hashed timing wheel, each slot holds the timers expiring at the ticks mapped to it
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use log::info;
+
+/// Number of slots in the wheel, timers further than this are kept for more rounds
+const WHEEL_SLOTS: usize = 64;
+
+/// Callback of a timer, called in the timer interrupt
+pub type TimerCallback = Box<dyn FnOnce() + Send>;
+
+/// Identifier of a scheduled timer, used to cancel it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(usize);
+
+struct TimerEntry {
+    id: TimerId,
+    expire_tick: usize,
+    callback: TimerCallback,
+}
+
+pub struct TimerWheel {
+    slots: Vec<Vec<TimerEntry>>,
+    current_tick: usize,
+    next_id: usize,
+}
+
+impl TimerWheel {
+    /// Create an empty timer wheel
+    pub fn new() -> Self {
+        Self {
+            slots: (0..WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+            current_tick: 0,
+            next_id: 0,
+        }
+    }
+
+    /// Add a timer which expires `delay_ticks` ticks later, at least one tick
+    pub fn add(&mut self, delay_ticks: usize, callback: TimerCallback) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        let expire_tick = self.current_tick.saturating_add(delay_ticks.max(1));
+        self.slots[expire_tick % WHEEL_SLOTS].push(TimerEntry {
+            id,
+            expire_tick,
+            callback,
+        });
+        id
+    }
+
+    /// Remove a pending timer, return false if it doesn't exist
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        for slot in self.slots.iter_mut() {
+            if let Some(pos) = slot.iter().position(|entry| entry.id == id) {
+                slot.swap_remove(pos);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Advance the wheel by one tick and return the callbacks of the expired timers
+    pub fn advance(&mut self) -> Vec<TimerCallback> {
+        self.current_tick += 1;
+        let now = self.current_tick;
+        let slot = &mut self.slots[now % WHEEL_SLOTS];
+        let mut expired = vec![];
+        let mut i = 0;
+        while i < slot.len() {
+            if slot[i].expire_tick <= now {
+                expired.push(slot.swap_remove(i).callback);
+            } else {
+                i += 1;
+            }
+        }
+        expired
+    }
+}
+
+#[allow(unused)]
+pub fn timer_wheel_test() {
+    info!("Testing timer wheel...");
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    static CANCELED_FIRED: AtomicBool = AtomicBool::new(false);
+    let mut wheel = TimerWheel::new();
+    wheel.add(1, Box::new(|| assert_eq!(FIRED.fetch_add(1, Ordering::Relaxed), 0)));
+    // in the same slot as the first one, but one round later
+    wheel.add(
+        WHEEL_SLOTS + 1,
+        Box::new(|| assert_eq!(FIRED.fetch_add(1, Ordering::Relaxed), 1)),
+    );
+    let canceled = wheel.add(3, Box::new(|| CANCELED_FIRED.store(true, Ordering::Relaxed)));
+    assert!(wheel.cancel(canceled));
+    assert!(!wheel.cancel(canceled));
+    for _ in 0..WHEEL_SLOTS + 1 {
+        for callback in wheel.advance() {
+            callback();
+        }
+    }
+    assert_eq!(FIRED.load(Ordering::Relaxed), 2);
+    assert!(!CANCELED_FIRED.load(Ordering::Relaxed));
+    info!("Timer wheel test passed!");
+}
@@ -0,0 +1,52 @@
+//! Trap context module
+//! Define the register snapshot saved on trap entry and restored on trap return
+
+use riscv::register::sstatus::{self, SPP, Sstatus};
+
+/// Trap context, the layout must be kept in sync with the trap entry assembly
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapContext {
+    /// General purpose registers x0 ~ x31
+    pub x: [usize; 32],
+    /// Supervisor status register
+    pub sstatus: Sstatus,
+    /// Supervisor exception program counter
+    pub sepc: usize,
+    /// Kernel space satp token, used by `__alltraps` to switch to kernel space
+    pub kernel_satp: usize,
+    /// Kernel stack pointer of the current task
+    pub kernel_sp: usize,
+    /// Virtual address of the user trap handler
+    pub trap_handler: usize,
+    /// Id of the hart the task returns to, `__alltraps` puts it back into tp
+    pub hart_id: usize,
+}
+
+impl TrapContext {
+    /// Set the stack pointer(x2) of the context
+    pub fn set_sp(&mut self, sp: usize) {
+        self.x[2] = sp;
+    }
+
+    /// Create the initial trap context of an user program,
+    /// `__restore` with it will enter U-mode at `entry` with stack pointer `sp`
+    pub fn app_init_context(
+        entry: usize, sp: usize, kernel_satp: usize, kernel_sp: usize, trap_handler: usize,
+    ) -> Self {
+        let mut sstatus = sstatus::read();
+        // return to U-mode after sret
+        sstatus.set_spp(SPP::User);
+        let mut cx = Self {
+            x: [0; 32],
+            sstatus,
+            sepc: entry,
+            kernel_satp,
+            kernel_sp,
+            trap_handler,
+            hart_id: 0,
+        };
+        cx.set_sp(sp);
+        cx
+    }
+}
@@ -0,0 +1,234 @@
+//! Trap handling module
+//! Install the trap vector, save/restore the TrapContext and dispatch traps by scause
+
+mod context;
+
+use core::arch::{asm, global_asm};
+
+pub use context::TrapContext;
+use log::{error, warn};
+use riscv::{
+    interrupt::{
+        Trap,
+        supervisor::{Exception, Interrupt},
+    },
+    register::{
+        scause, stval,
+        stvec::{self, Stvec, TrapMode},
+    },
+};
+
+use crate::{
+    config::{TRAMPOLINE, TRAP_CONTEXT},
+    cpu::hart_id,
+    drivers::plic,
+    memory::{AllocError, KERNEL_SPACE, PTEFlags, VirtAddr},
+    syscall::syscall,
+    task::{
+        current_task, current_trap_cx, current_user_token, exit_current_and_run_next, suspend_current_and_run_next,
+    },
+    timer,
+};
+
+global_asm!(include_str!("kernel_trap.S"));
+global_asm!(include_str!("trampoline.S"));
+
+/// Initialize the trap handling, install the kernel trap entry into stvec
+pub fn init() {
+    set_kernel_trap_entry();
+}
+
+/// Set stvec to the kernel trap entry, traps from S-mode will go to `__kernel_trap`
+fn set_kernel_trap_entry() {
+    unsafe extern "C" {
+        fn __kernel_trap();
+    }
+    let mut vector = Stvec::from_bits(0);
+    vector.set_address(__kernel_trap as usize);
+    vector.set_trap_mode(TrapMode::Direct);
+    unsafe {
+        stvec::write(vector);
+    }
+}
+
+/// Set stvec to the trampoline, traps from U-mode will go to `__alltraps`
+/// `__alltraps` is mapped at TRAMPOLINE in both user and kernel space
+fn set_user_trap_entry() {
+    let mut vector = Stvec::from_bits(0);
+    vector.set_address(TRAMPOLINE);
+    vector.set_trap_mode(TrapMode::Direct);
+    unsafe {
+        stvec::write(vector);
+    }
+}
+
+/// Trap handler for traps from U-mode, `__alltraps` jumps here after switching to kernel space
+#[unsafe(no_mangle)]
+pub fn trap_handler() -> ! {
+    set_kernel_trap_entry();
+    let cx = current_trap_cx();
+    let scause = scause::read();
+    let stval = stval::read();
+    match scause.cause().try_into::<Interrupt, Exception>() {
+        Ok(Trap::Exception(Exception::UserEnvCall)) => {
+            // return to the next instruction of ecall
+            cx.sepc += 4;
+            let result = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12], cx.x[13], cx.x[14], cx.x[15]]);
+            // the trap context may be changed by the syscall(e.g. exec), get it again
+            current_trap_cx().x[10] = result as usize;
+        }
+        Ok(Trap::Exception(
+            fault @ (Exception::InstructionPageFault | Exception::LoadPageFault | Exception::StorePageFault),
+        )) => match handle_user_page_fault(fault, stval) {
+            Ok(true) => {}
+            Ok(false) => {
+                report_user_fault(cx, fault, stval);
+                exit_current_and_run_next(-2);
+            }
+            Err(AllocError) => {
+                error!(
+                    "[kernel] Out of memory, pid {} is killed",
+                    current_task().unwrap().getpid()
+                );
+                exit_current_and_run_next(-12);
+            }
+        },
+        Ok(Trap::Exception(fault)) => {
+            report_user_fault(cx, fault, stval);
+            exit_current_and_run_next(-2);
+        }
+        Ok(Trap::Interrupt(Interrupt::SupervisorTimer)) => {
+            timer::handle_timer_interrupt();
+            // the time slice is used up
+            suspend_current_and_run_next();
+        }
+        Ok(Trap::Interrupt(Interrupt::SupervisorExternal)) => plic::handle_irq(),
+        Ok(Trap::Interrupt(interrupt)) => handle_unexpected_interrupt(interrupt),
+        Err(_) => panic!("Unknown trap: scause = {:#x}, stval = {:#x}", scause.bits(), stval),
+    }
+    trap_return()
+}
+
+/// Return to U-mode of the current task
+/// jump to `__restore` through its address in the trampoline
+pub fn trap_return() -> ! {
+    set_user_trap_entry();
+    // the task may run on a different hart next time
+    current_trap_cx().hart_id = hart_id();
+    let user_satp = current_user_token();
+    unsafe extern "C" {
+        fn __alltraps();
+        fn __restore();
+    }
+    let restore_va = __restore as usize - __alltraps as usize + TRAMPOLINE;
+    unsafe {
+        asm!(
+            "fence.i",
+            "jr {restore_va}",
+            restore_va = in(reg) restore_va,
+            in("a0") TRAP_CONTEXT,
+            in("a1") user_satp,
+            options(noreturn)
+        );
+    }
+}
+
+/// Trap handler for traps from S-mode, called by `__kernel_trap`
+#[unsafe(no_mangle)]
+pub fn kernel_trap_handler(cx: &mut TrapContext) {
+    let scause = scause::read();
+    let stval = stval::read();
+    match scause.cause().try_into::<Interrupt, Exception>() {
+        Ok(Trap::Exception(
+            fault @ (Exception::InstructionPageFault | Exception::LoadPageFault | Exception::StorePageFault),
+        )) => handle_page_fault(cx, fault, stval),
+        Ok(Trap::Exception(Exception::IllegalInstruction)) => handle_illegal_instruction(cx, stval),
+        Ok(Trap::Exception(Exception::Breakpoint)) => handle_breakpoint(cx),
+        Ok(Trap::Exception(fault)) => handle_access_fault(cx, fault, stval),
+        Ok(Trap::Interrupt(Interrupt::SupervisorTimer)) => timer::handle_timer_interrupt(),
+        Ok(Trap::Interrupt(Interrupt::SupervisorExternal)) => plic::handle_irq(),
+        Ok(Trap::Interrupt(interrupt)) => handle_unexpected_interrupt(interrupt),
+        Err(_) => panic!("Unknown trap: scause = {:#x}, stval = {:#x}", scause.bits(), stval),
+    }
+}
+
+/// Page faults in the kernel are always fatal
+fn handle_page_fault(cx: &TrapContext, fault: Exception, stval: usize) {
+    report_fault(cx, fault, stval, stval);
+    panic!("Unhandled {:?} in kernel", fault);
+}
+
+/// stval holds the faulting instruction bits (or zero if the hart doesn't provide them)
+fn handle_illegal_instruction(cx: &TrapContext, stval: usize) {
+    report_fault(cx, Exception::IllegalInstruction, stval, cx.sepc);
+    panic!("Illegal instruction {:#x} in kernel", stval);
+}
+
+/// Skip the `ebreak` and continue, so it can be used as a debug probe
+fn handle_breakpoint(cx: &mut TrapContext) {
+    warn!("[kernel] Breakpoint at sepc = {:#x}", cx.sepc);
+    cx.sepc += instruction_len(cx.sepc);
+}
+
+/// Access faults and misaligned accesses, the kernel can't recover from them
+fn handle_access_fault(cx: &TrapContext, fault: Exception, stval: usize) {
+    report_fault(cx, fault, stval, stval);
+    panic!("Unhandled {:?} in kernel", fault);
+}
+
+/// Only the timer and external interrupts are enabled, so any other interrupt here is a bug
+fn handle_unexpected_interrupt(interrupt: Interrupt) {
+    panic!("Unexpected interrupt {:?} in kernel", interrupt);
+}
+
+/// Print a readable report of the fault, including the VmArea which contains `fault_addr`
+fn report_fault(cx: &TrapContext, fault: Exception, stval: usize, fault_addr: usize) {
+    error!("[kernel] {:?}: sepc = {:#x}, stval = {:#x}", fault, cx.sepc, stval);
+    let kernel_space = KERNEL_SPACE.lock();
+    match kernel_space.find_area(VirtAddr::from(fault_addr).floor()) {
+        Some(area) => error!("[kernel] faulting area: {:?}", area),
+        None => error!("[kernel] faulting address {:#x} is not in any area", fault_addr),
+    }
+}
+
+/// Let the memory space of the current task resolve the page fault, e.g. copy-on-write
+/// return Ok(false) if it is a real fault
+fn handle_user_page_fault(fault: Exception, stval: usize) -> Result<bool, AllocError> {
+    let access = match fault {
+        Exception::InstructionPageFault => PTEFlags::X,
+        Exception::LoadPageFault => PTEFlags::R,
+        _ => PTEFlags::W,
+    };
+    let task = current_task().expect("No current task");
+    let mut task_inner = task.inner_exclusive_access();
+    task_inner
+        .memory_space
+        .handle_page_fault(VirtAddr::from(stval).floor(), access)
+}
+
+/// Print a readable report of a fault in the current task, the task will be killed
+fn report_user_fault(cx: &TrapContext, fault: Exception, stval: usize) {
+    let task = current_task().expect("No current task");
+    error!(
+        "[kernel] {:?} in application(pid {}): sepc = {:#x}, stval = {:#x}",
+        fault,
+        task.getpid(),
+        cx.sepc,
+        stval
+    );
+    let fault_addr = match fault {
+        Exception::IllegalInstruction => cx.sepc,
+        _ => stval,
+    };
+    let task_inner = task.inner_exclusive_access();
+    match task_inner.memory_space.find_area(VirtAddr::from(fault_addr).floor()) {
+        Some(area) => error!("[kernel] faulting area: {:?}", area),
+        None => error!("[kernel] faulting address {:#x} is not in any area", fault_addr),
+    }
+}
+
+/// Get the length of the instruction at `addr`, compressed instructions are 2 bytes
+fn instruction_len(addr: usize) -> usize {
+    let low_bits = unsafe { (addr as *const u16).read_volatile() };
+    if low_bits & 0b11 == 0b11 { 4 } else { 2 }
+}
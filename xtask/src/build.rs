@@ -10,6 +10,10 @@ pub struct BuildArgs {
     /// set kernel log level from: ERROR(default), WARN, INFO, DEBUG, TRACE
     #[arg(long)]
     log: Option<String>,
+
+    /// enable kernel features, e.g. lockdep
+    #[arg(long)]
+    features: Option<String>,
 }
 
 impl BuildArgs {
@@ -30,6 +34,10 @@ impl BuildArgs {
         if self.release {
             args.push("--release");
         }
+        if let Some(features) = &self.features {
+            args.push("--features");
+            args.push(features);
+        }
 
         // rustc flags
         let rustc_args = vec![
@@ -6,11 +6,20 @@ use clap::Args;
 pub struct QemuArgs {
     #[arg(short, long, default_value_t = false)]
     debug: bool,
+    /// number of harts
+    #[arg(long, default_value_t = 1)]
+    smp: usize,
+    /// raw disk image attached as a virtio-blk device
+    #[arg(long)]
+    drive: Option<String>,
 }
 
 impl QemuArgs {
     pub fn run(&self) {
+        let smp = self.smp.to_string();
         let mut args = vec![
+            "-smp",
+            &smp,
             "-machine",
             "virt",
             "-nographic",
@@ -19,6 +28,16 @@ impl QemuArgs {
             "-device",
             "loader,file=target/riscv64gc-unknown-none-elf/debug/kernel,addr=0x80200000",
         ];
+        let drive;
+        if let Some(image) = &self.drive {
+            drive = format!("file={},if=none,format=raw,id=x0", image);
+            args.extend([
+                "-drive",
+                &drive,
+                "-device",
+                "virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0",
+            ]);
+        }
         if self.debug {
             args.push("-s");
             args.push("-S");